@@ -0,0 +1,817 @@
+//! The readers of sources and the writers of results that are shared by the
+//! backends, which pass record batches to and from them.
+
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::{fs, io};
+
+use anyhow::{anyhow, Result};
+use chrono::Datelike;
+use log::{debug, warn};
+
+// Use the arrow crate of whichever backend is available
+#[cfg(all(feature = "datafusion", not(feature = "arrow")))]
+use datafusion::{arrow, parquet};
+
+use arrow::array::{
+    as_boolean_array, as_primitive_array, as_string_array, Array, ArrayRef, BooleanArray,
+    Date32Array, Float64Array, Int64Array, StringArray, TimestampMicrosecondArray,
+};
+use arrow::datatypes::*;
+use arrow::error::Result as ArrowResult;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use arrow::util::pretty::pretty_format_batches;
+use arrow::{csv, ipc, json};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::arrow_writer;
+use parquet::file::properties::WriterProperties;
+
+use crate::backends;
+use crate::database::{self, DatabaseScheme, DatabaseSpec};
+use crate::delimited::{self, CsvOptions};
+use crate::encoding;
+use crate::on_error::{self, OnError};
+use crate::renderers::TextTable;
+use crate::schema::SchemaSpec;
+use crate::xlsx::{self, Cell, ColumnType, Sheet};
+use crate::{
+    check_csv_append_header, get_dest_from_to, get_parquet_append_paths, JsonStyle, OutputFormat,
+    OutputOptions, ParquetOptions, SourceSpec,
+};
+
+// The number of days from 0001-01-01 (CE) to 1970-01-01
+const UNIX_EPOCH_DAYS_FROM_CE: i32 = 719_163;
+
+// The rows the column types are inferred from by default, as in DataFusion
+const DEFAULT_INFER_ROWS: usize = 1000;
+
+// A schema together with the record batches read from a source
+pub type SchemaWithBatches = (
+    SchemaRef,
+    Box<dyn Iterator<Item = ArrowResult<RecordBatch>>>,
+);
+
+/// Reads a source from stdin in the given format into record batches.
+pub fn get_stdin_reader(stdin_format: &str, spec: &SourceSpec) -> Result<SchemaWithBatches> {
+    // Buffer stdin since the csv, json and IPC file readers require seeking
+    let mut buf: Vec<u8> = Vec::new();
+    io::stdin().read_to_end(&mut buf)?;
+    if ["csv", "tsv", "json", "ndjson"].contains(&stdin_format) {
+        buf = encoding::transcode(buf, &spec.encoding);
+    }
+    let cursor = io::Cursor::new(buf);
+
+    let reader: SchemaWithBatches = match stdin_format {
+        "arrows" => {
+            let reader = ipc::reader::StreamReader::try_new(cursor, None)?;
+            (reader.schema(), Box::new(reader))
+        }
+        "arrow" => {
+            let reader = ipc::reader::FileReader::try_new(cursor, None)?;
+            (reader.schema(), Box::new(reader))
+        }
+        "csv" | "tsv" => get_csv_reader(cursor, stdin_format, spec)?,
+        "json" | "ndjson" => get_json_reader(cursor, spec)?,
+        _ => {
+            return Err(anyhow!(
+                "Reading format={stdin_format:?} from stdin is not supported."
+            ))
+        }
+    };
+    Ok(reader)
+}
+
+/// Reads a csv (or tsv) source after normalising it with the csv options
+pub fn get_csv_reader(
+    input: impl Read,
+    path: &str,
+    spec: &SourceSpec,
+) -> Result<SchemaWithBatches> {
+    let csv_options = &spec.csv;
+    let (buf, schema) = if spec.on_error == OnError::Fail {
+        let buf = delimited::normalise(input, path, csv_options)?;
+        let schema = get_csv_schema(&buf, spec)?;
+        (buf, schema)
+    } else {
+        let (mut rows, mut rejected) = delimited::split_rows(input, path, csv_options)?;
+        let schema = get_csv_schema(&rows.to_bytes(), spec)?;
+        rejected.extend(rows.retain_valid(|buf| {
+            let reader = csv::ReaderBuilder::new()
+                .has_header(csv_options.header)
+                .with_schema(Arc::new(schema.clone()))
+                .build(io::Cursor::new(buf))?;
+            for rb in reader {
+                rb?;
+            }
+            Ok(())
+        }));
+        rejected.sort_by_key(|r| r.line);
+        on_error::report(&spec.source, &rejected, &spec.on_error)?;
+        (rows.to_bytes(), schema)
+    };
+    let reader = csv::ReaderBuilder::new()
+        .has_header(csv_options.header)
+        .with_schema(Arc::new(schema))
+        .build(io::Cursor::new(buf))?;
+    let schema = reader.schema();
+    if csv_options.null_value.is_some() {
+        // null values were emptied, so also read empty strings as nulls
+        let reader = reader.map(|rb| rb.and_then(get_batch_with_empty_strings_as_nulls));
+        Ok((schema, Box::new(reader)))
+    } else {
+        Ok((schema, Box::new(reader)))
+    }
+}
+
+/// Infers the schema of normalised csv, using the types of the schema spec
+/// for the columns it lists.
+fn get_csv_schema(buf: &[u8], spec: &SourceSpec) -> Result<Schema> {
+    let (inferred, _) = csv::reader::infer_file_schema(
+        io::Cursor::new(buf),
+        b',',
+        Some(spec.infer_rows.unwrap_or(DEFAULT_INFER_ROWS)),
+        spec.csv.header,
+    )?;
+    match &spec.schema {
+        Some(schema_spec) => get_schema_with_overrides(inferred, schema_spec),
+        None => Ok(inferred),
+    }
+}
+
+/// Reads a newline delimited json source
+pub fn get_json_reader(mut input: impl Read, spec: &SourceSpec) -> Result<SchemaWithBatches> {
+    let mut buf: Vec<u8> = Vec::new();
+    input.read_to_end(&mut buf)?;
+    let (buf, schema) = if spec.on_error == OnError::Fail {
+        let schema = get_json_schema(&buf, spec)?;
+        (buf, schema)
+    } else {
+        let (mut rows, mut rejected) = on_error::split_ndjson(&buf);
+        let schema = get_json_schema(&rows.to_bytes(), spec)?;
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        rejected.extend(rows.retain_valid(|buf| {
+            // the json reader reads values that don't match the type of their
+            // column as nulls, so compare the numbers of nulls
+            let mut nulls = vec![0; names.len()];
+            let reader = json::ReaderBuilder::new()
+                .with_schema(Arc::new(schema.clone()))
+                .build(io::Cursor::new(buf))?;
+            for rb in reader {
+                for (count, column) in nulls.iter_mut().zip(rb?.columns()) {
+                    *count += column.null_count();
+                }
+            }
+            let expected = on_error::count_json_nulls(buf, &names);
+            match (0..names.len()).find(|i| nulls[*i] != expected[*i]) {
+                Some(i) => Err(anyhow!(
+                    "A value of column {:?} doesn't match its type {}",
+                    names[i],
+                    schema.field(i).data_type()
+                )),
+                None => Ok(()),
+            }
+        }));
+        rejected.sort_by_key(|r| r.line);
+        on_error::report(&spec.source, &rejected, &spec.on_error)?;
+        (rows.to_bytes(), schema)
+    };
+    let reader = json::ReaderBuilder::new()
+        .with_schema(Arc::new(schema))
+        .build(io::Cursor::new(buf))?;
+    Ok((reader.schema(), Box::new(reader)))
+}
+
+/// Infers the schema of newline delimited json, using the types of the
+/// schema spec for the columns it lists.
+fn get_json_schema(buf: &[u8], spec: &SourceSpec) -> Result<Schema> {
+    let inferred = json::reader::infer_json_schema_from_seekable(
+        &mut io::BufReader::new(io::Cursor::new(buf)),
+        Some(spec.infer_rows.unwrap_or(DEFAULT_INFER_ROWS)),
+    )?;
+    match &spec.schema {
+        Some(schema_spec) => get_schema_with_overrides(inferred, schema_spec),
+        None => Ok(inferred),
+    }
+}
+
+/// Returns the inferred schema with the types of the columns given in the
+/// schema spec replaced.
+pub fn get_schema_with_overrides(inferred: Schema, schema_spec: &SchemaSpec) -> Result<Schema> {
+    let mut fields = inferred.fields().clone();
+    for (name, column_type) in schema_spec {
+        let field = fields
+            .iter_mut()
+            .find(|f| f.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&String> = inferred.fields().iter().map(|f| f.name()).collect();
+                anyhow!(
+                    "Column {name:?} of the schema was not found in the source columns {names:?}."
+                )
+            })?;
+        *field = Field::new(name, get_data_type(column_type), true);
+    }
+    Ok(Schema::new(fields))
+}
+
+fn get_data_type(column_type: &crate::schema::ColumnType) -> DataType {
+    use crate::schema::ColumnType;
+    match column_type {
+        ColumnType::Boolean => DataType::Boolean,
+        ColumnType::Int32 => DataType::Int32,
+        ColumnType::Int64 => DataType::Int64,
+        ColumnType::Float32 => DataType::Float32,
+        ColumnType::Float64 => DataType::Float64,
+        ColumnType::Decimal(precision, scale) => DataType::Decimal128(*precision, *scale),
+        ColumnType::Utf8 => DataType::Utf8,
+        ColumnType::Date => DataType::Date32,
+        ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, None),
+    }
+}
+
+fn get_batch_with_empty_strings_as_nulls(rb: RecordBatch) -> ArrowResult<RecordBatch> {
+    let columns = rb
+        .columns()
+        .iter()
+        .map(|column| match column.data_type() {
+            DataType::Utf8 => Arc::new(
+                as_string_array(column)
+                    .iter()
+                    .map(|value| value.filter(|s| !s.is_empty()))
+                    .collect::<StringArray>(),
+            ) as ArrayRef,
+            _ => column.clone(),
+        })
+        .collect();
+    RecordBatch::try_new(rb.schema(), columns)
+}
+
+/// Converts a worksheet into a record batch using the column types inferred
+/// from its cells.
+pub fn get_sheet_reader(path: &str, sheet: Option<&str>) -> Result<SchemaWithBatches> {
+    let sheet = xlsx::read_sheet(path, sheet)?;
+    let mut fields: Vec<Field> = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for (i, column_type) in sheet.column_types().into_iter().enumerate() {
+        let cells = sheet.rows.iter().map(|row| &row[i]);
+        let (data_type, column): (DataType, ArrayRef) = match column_type {
+            ColumnType::Boolean => (
+                DataType::Boolean,
+                Arc::new(
+                    cells
+                        .map(|c| match c {
+                            Cell::Bool(b) => Some(*b),
+                            _ => None,
+                        })
+                        .collect::<BooleanArray>(),
+                ),
+            ),
+            ColumnType::Int64 => (
+                DataType::Int64,
+                Arc::new(
+                    cells
+                        .map(|c| match c {
+                            Cell::Int(i) => Some(*i),
+                            _ => None,
+                        })
+                        .collect::<Int64Array>(),
+                ),
+            ),
+            ColumnType::Float64 => (
+                DataType::Float64,
+                Arc::new(cells.map(Cell::as_f64).collect::<Float64Array>()),
+            ),
+            ColumnType::Date => (
+                DataType::Date32,
+                Arc::new(
+                    cells
+                        .map(|c| {
+                            c.as_datetime()
+                                .map(|dt| dt.date().num_days_from_ce() - UNIX_EPOCH_DAYS_FROM_CE)
+                        })
+                        .collect::<Date32Array>(),
+                ),
+            ),
+            ColumnType::Timestamp => (
+                DataType::Timestamp(TimeUnit::Microsecond, None),
+                Arc::new(
+                    cells
+                        .map(|c| c.as_datetime().map(|dt| dt.timestamp_micros()))
+                        .collect::<TimestampMicrosecondArray>(),
+                ),
+            ),
+            ColumnType::Utf8 => (
+                DataType::Utf8,
+                Arc::new(cells.map(Cell::as_string).collect::<StringArray>()),
+            ),
+        };
+        fields.push(Field::new(&sheet.columns[i], data_type, true));
+        columns.push(column);
+    }
+
+    let schema = Arc::new(Schema::new(fields));
+    let rb = RecordBatch::try_new(schema.clone(), columns)?;
+    Ok((schema, Box::new(std::iter::once(Ok(rb)))))
+}
+
+pub fn write_results_with_arrow(
+    rbs: &[RecordBatch],
+    to: &str,
+    format: &OutputFormat,
+    output_options: &OutputOptions,
+) -> Result<()> {
+    let append = output_options.append;
+    if *format == OutputFormat::database {
+        let extension_dir = output_options.duckdb_extension_dir.as_deref();
+        return write_results_to_database(rbs, to, append, extension_dir);
+    }
+    if append && *format == OutputFormat::parquet {
+        return append_record_batches_to_parquet(rbs, to, &output_options.parquet);
+    }
+
+    let csv_options = &output_options.csv;
+    let mut has_headers = csv_options.header;
+    if has_headers
+        && append
+        && [OutputFormat::csv, OutputFormat::tsv].contains(format)
+        && !rbs.is_empty()
+    {
+        let columns: Vec<String> = rbs[0]
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().to_string())
+            .collect();
+        has_headers = check_csv_append_header(to, &columns, csv_options)?;
+    }
+
+    let mut dest = get_dest_from_to(to, append)?;
+
+    match format {
+        OutputFormat::csv | OutputFormat::tsv => {
+            write_record_batches_to_csv(rbs, &mut dest, to, has_headers, csv_options)?
+        }
+        OutputFormat::json => {
+            write_record_batches_to_json(rbs, &mut dest, &output_options.json_style)?
+        }
+        OutputFormat::parquet => {
+            write_record_batches_to_parquet(rbs, &mut dest, &output_options.parquet)?
+        }
+        OutputFormat::table => write_record_batches_to_table(rbs, &mut dest)?,
+        OutputFormat::arrow => write_record_batches_to_arrow(rbs, &mut dest)?,
+        OutputFormat::arrows => write_record_batches_to_arrows(rbs, &mut dest)?,
+        OutputFormat::xlsx => write_record_batches_to_xlsx(rbs, &mut dest)?,
+        OutputFormat::markdown | OutputFormat::html | OutputFormat::latex => {
+            write_record_batches_to_text_table(rbs, &mut dest, format)?
+        }
+        OutputFormat::database => unreachable!("Database outputs are written above"),
+    }
+
+    dest.finish()
+}
+
+/// Writes the results to a table of a database, given as `<uri>#<table>`,
+/// with the writer of the backend for its scheme.
+fn write_results_to_database(
+    rbs: &[RecordBatch],
+    to: &str,
+    append: bool,
+    extension_dir: Option<&str>,
+) -> Result<()> {
+    let (database, table) = to
+        .split_once('#')
+        .ok_or(anyhow!("No table name given in {to:?}."))?;
+    debug!("database={:?}, table={table:?}", database::redact(database));
+
+    if rbs.is_empty() {
+        warn!("No results to write to {table}.");
+        return Ok(());
+    }
+
+    let database = DatabaseSpec::parse(database)?;
+    if database.read_only {
+        return Err(anyhow!(
+            "Cannot write to the table {table} of {:?}, which is opened read_only.",
+            database.path
+        ));
+    }
+    match database.scheme {
+        #[cfg(feature = "duckdb")]
+        DatabaseScheme::DuckDb => backends::duckdb::write_record_batches_to_duckdb(
+            rbs,
+            &database.path,
+            table,
+            append,
+            extension_dir,
+        ),
+        #[cfg(feature = "sqlite")]
+        DatabaseScheme::Sqlite => {
+            backends::sqlite::write_record_batches_to_sqlite(rbs, &database.path, table, append)
+        }
+        #[cfg(feature = "postgres")]
+        DatabaseScheme::Postgres => {
+            backends::postgres::write_record_batches_to_postgres(rbs, &database, table, append)
+        }
+        #[allow(unreachable_patterns)]
+        scheme => Err(anyhow!("Writing to {scheme} databases is not supported.")),
+    }
+}
+
+fn write_record_batches_to_csv(
+    rbs: &[RecordBatch],
+    dest: &mut dyn Write,
+    to: &str,
+    has_headers: bool,
+    csv_options: &CsvOptions,
+) -> Result<()> {
+    if csv_options.is_simple() {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(has_headers)
+            .with_delimiter(csv_options.get_delimiter(to))
+            .build(dest);
+        for rb in rbs {
+            writer.write(rb)?;
+        }
+    } else {
+        // arrow can't quote or write nulls differently, so rewrite its output
+        let mut buf: Vec<u8> = Vec::new();
+        {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(has_headers)
+                .build(&mut buf);
+            for rb in rbs {
+                writer.write(rb)?;
+            }
+        }
+        delimited::rewrite(&buf, dest, to, has_headers, csv_options)?;
+    }
+    Ok(())
+}
+
+fn write_record_batches_to_json(
+    rbs: &[RecordBatch],
+    dest: &mut dyn Write,
+    json_style: &JsonStyle,
+) -> Result<()> {
+    let rbs = get_json_compatible_batches(rbs)?;
+    match json_style {
+        JsonStyle::lines => {
+            let mut writer = json::LineDelimitedWriter::new(dest);
+            writer.write_batches(&rbs)?;
+            writer.finish()?;
+        }
+        JsonStyle::array | JsonStyle::pretty => {
+            let rows = json::writer::record_batches_to_json_rows(&rbs)?;
+            if *json_style == JsonStyle::pretty {
+                serde_json::to_writer_pretty(&mut *dest, &rows)?;
+            } else {
+                serde_json::to_writer(&mut *dest, &rows)?;
+            }
+            dest.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Converts columns with types that the arrow json writer cannot serialise
+/// (e.g. decimals, binary or temporal values nested in lists) to strings, so
+/// that the output is the same whichever backend produced the results.
+fn get_json_compatible_batches(rbs: &[RecordBatch]) -> Result<Vec<RecordBatch>> {
+    let mut json_rbs: Vec<RecordBatch> = Vec::new();
+    for rb in rbs {
+        let schema = rb.schema();
+        let mut fields: Vec<Field> = Vec::new();
+        let mut columns: Vec<ArrayRef> = Vec::new();
+        for (field, column) in schema.fields().iter().zip(rb.columns()) {
+            if is_json_compatible(field.data_type(), false) {
+                fields.push(field.clone());
+                columns.push(column.clone());
+            } else {
+                debug!("Converting column {} to strings for json", field.name());
+                let values = (0..column.len())
+                    .map(|i| match column.is_null(i) {
+                        true => Ok(None),
+                        false => array_value_to_string(column, i).map(Some),
+                    })
+                    .collect::<ArrowResult<StringArray>>()?;
+                fields.push(Field::new(
+                    field.name(),
+                    DataType::Utf8,
+                    field.is_nullable(),
+                ));
+                columns.push(Arc::new(values));
+            }
+        }
+        json_rbs.push(RecordBatch::try_new(
+            Arc::new(Schema::new(fields)),
+            columns,
+        )?);
+    }
+    Ok(json_rbs)
+}
+
+fn is_json_compatible(data_type: &DataType, in_list: bool) -> bool {
+    match data_type {
+        DataType::Null
+        | DataType::Boolean
+        | DataType::Utf8
+        | DataType::LargeUtf8
+        | DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float32
+        | DataType::Float64 => true,
+        DataType::List(field) | DataType::LargeList(field) => {
+            is_json_compatible(field.data_type(), true)
+        }
+        DataType::Struct(fields) => fields
+            .iter()
+            .all(|f| is_json_compatible(f.data_type(), false)),
+        // The following are only supported outside of lists
+        DataType::Date32
+        | DataType::Date64
+        | DataType::Timestamp(_, _)
+        | DataType::Time32(TimeUnit::Second | TimeUnit::Millisecond)
+        | DataType::Time64(TimeUnit::Microsecond | TimeUnit::Nanosecond)
+        | DataType::Duration(_) => !in_list,
+        DataType::Dictionary(_, value_type) => !in_list && is_json_compatible(value_type, false),
+        _ => false,
+    }
+}
+
+/// Builds the parquet writer properties for the options with the given
+/// parquet crate. It's a macro since DataFusion's native writer needs them
+/// for its own version of the crate.
+macro_rules! writer_properties {
+    ($parquet:ident, $parquet_options:expr) => {{
+        use $crate::{ParquetCompression, ParquetOptions, ParquetStatistics};
+        use $parquet::basic::Compression;
+        use $parquet::file::properties::{EnabledStatistics, WriterProperties};
+
+        let parquet_options: &ParquetOptions = $parquet_options;
+        let mut builder = WriterProperties::builder();
+        if let Some(compression) = parquet_options.compression {
+            builder = builder.set_compression(match compression {
+                ParquetCompression::uncompressed => Compression::UNCOMPRESSED,
+                ParquetCompression::snappy => Compression::SNAPPY,
+                ParquetCompression::gzip => Compression::GZIP,
+                ParquetCompression::brotli => Compression::BROTLI,
+                ParquetCompression::lz4 => Compression::LZ4,
+                ParquetCompression::zstd => Compression::ZSTD,
+            });
+        }
+        if let Some(row_group_size) = parquet_options.row_group_size {
+            builder = builder.set_max_row_group_size(row_group_size);
+        }
+        if let Some(statistics) = parquet_options.statistics {
+            builder = builder.set_statistics_enabled(match statistics {
+                ParquetStatistics::none => EnabledStatistics::None,
+                ParquetStatistics::chunk => EnabledStatistics::Chunk,
+                ParquetStatistics::page => EnabledStatistics::Page,
+            });
+        }
+        if let Some(dictionary) = parquet_options.dictionary {
+            builder = builder.set_dictionary_enabled(dictionary);
+        }
+        builder.build()
+    }};
+}
+pub(crate) use writer_properties;
+
+fn get_writer_properties(parquet_options: &ParquetOptions) -> WriterProperties {
+    writer_properties!(parquet, parquet_options)
+}
+
+pub fn write_record_batches_to_parquet(
+    rbs: &[RecordBatch],
+    dest: &mut dyn Write,
+    parquet_options: &ParquetOptions,
+) -> Result<()> {
+    if rbs.is_empty() {
+        return Ok(());
+    }
+
+    let schema = rbs[0].schema();
+    {
+        let mut writer = arrow_writer::ArrowWriter::try_new(
+            dest,
+            schema,
+            Some(get_writer_properties(parquet_options)),
+        )?;
+
+        for rb in rbs {
+            writer.write(rb)?;
+        }
+        writer.close()?;
+    }
+    Ok(())
+}
+
+fn append_record_batches_to_parquet(
+    rbs: &[RecordBatch],
+    to: &str,
+    parquet_options: &ParquetOptions,
+) -> Result<()> {
+    if rbs.is_empty() {
+        return Ok(());
+    }
+
+    let (part, existing) = get_parquet_append_paths(to)?;
+    if let Some(existing) = existing {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(fs::File::open(&existing)?)?;
+        let existing_fields: Vec<(&String, &DataType)> = builder
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| (f.name(), f.data_type()))
+            .collect();
+        let schema = rbs[0].schema();
+        let fields: Vec<(&String, &DataType)> = schema
+            .fields()
+            .iter()
+            .map(|f| (f.name(), f.data_type()))
+            .collect();
+        if existing_fields != fields {
+            return Err(anyhow!(
+                "Cannot append to {to:?}: the schema of {existing:?} {existing_fields:?} does not match {fields:?}."
+            ));
+        }
+    }
+
+    let mut dest = get_dest_from_to(&part, false)?;
+    write_record_batches_to_parquet(rbs, &mut dest, parquet_options)?;
+    dest.finish()
+}
+
+fn write_record_batches_to_arrow(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
+    if rbs.is_empty() {
+        return Ok(());
+    }
+
+    let schema = rbs[0].schema();
+    {
+        let mut writer = ipc::writer::FileWriter::try_new(dest, &schema)?;
+        for rb in rbs {
+            writer.write(rb)?;
+        }
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+fn write_record_batches_to_arrows(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
+    if rbs.is_empty() {
+        return Ok(());
+    }
+
+    let schema = rbs[0].schema();
+    {
+        let mut writer = ipc::writer::StreamWriter::try_new(dest, &schema)?;
+        for rb in rbs {
+            writer.write(rb)?;
+        }
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+fn write_record_batches_to_text_table(
+    rbs: &[RecordBatch],
+    dest: &mut dyn Write,
+    format: &OutputFormat,
+) -> Result<()> {
+    if rbs.is_empty() {
+        return Ok(());
+    }
+
+    let schema = rbs[0].schema();
+    let mut rows: Vec<Vec<Option<String>>> = Vec::new();
+    for rb in rbs {
+        for i in 0..rb.num_rows() {
+            let row = rb
+                .columns()
+                .iter()
+                .map(|column| match column.is_null(i) {
+                    true => Ok(None),
+                    false => array_value_to_string(column, i).map(Some),
+                })
+                .collect::<ArrowResult<Vec<Option<String>>>>()?;
+            rows.push(row);
+        }
+    }
+    let table = TextTable {
+        columns: schema.fields().iter().map(|f| f.name().clone()).collect(),
+        numeric: schema
+            .fields()
+            .iter()
+            .map(|f| {
+                matches!(
+                    f.data_type(),
+                    DataType::Int8
+                        | DataType::Int16
+                        | DataType::Int32
+                        | DataType::Int64
+                        | DataType::UInt8
+                        | DataType::UInt16
+                        | DataType::UInt32
+                        | DataType::UInt64
+                        | DataType::Float16
+                        | DataType::Float32
+                        | DataType::Float64
+                        | DataType::Decimal128(_, _)
+                        | DataType::Decimal256(_, _)
+                )
+            })
+            .collect(),
+        rows,
+    };
+
+    match format {
+        OutputFormat::markdown => table.write_markdown(dest),
+        OutputFormat::html => table.write_html(dest),
+        OutputFormat::latex => table.write_latex(dest),
+        _ => Err(anyhow!("format={format:?} is not a text table format.")),
+    }
+}
+
+fn write_record_batches_to_xlsx(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
+    if rbs.is_empty() {
+        return Ok(());
+    }
+
+    let mut sheet = Sheet {
+        columns: rbs[0]
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect(),
+        ..Default::default()
+    };
+    for rb in rbs {
+        for i in 0..rb.num_rows() {
+            let row = rb
+                .columns()
+                .iter()
+                .map(|column| get_xlsx_cell(column, i))
+                .collect::<Result<Vec<Cell>>>()?;
+            sheet.rows.push(row);
+        }
+    }
+    xlsx::write_sheet(&sheet, dest)
+}
+
+fn get_xlsx_cell(column: &ArrayRef, i: usize) -> Result<Cell> {
+    if column.is_null(i) {
+        return Ok(Cell::Empty);
+    }
+    let cell = match column.data_type() {
+        DataType::Boolean => Cell::Bool(as_boolean_array(column).value(i)),
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float16
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Decimal128(_, _) => Cell::Float(array_value_to_string(column, i)?.parse()?),
+        DataType::Date32 => as_primitive_array::<Date32Type>(column)
+            .value_as_date(i)
+            .map_or(Cell::Empty, Cell::Date),
+        DataType::Date64 => as_primitive_array::<Date64Type>(column)
+            .value_as_date(i)
+            .map_or(Cell::Empty, Cell::Date),
+        DataType::Timestamp(unit, _) => match unit {
+            TimeUnit::Second => {
+                as_primitive_array::<TimestampSecondType>(column).value_as_datetime(i)
+            }
+            TimeUnit::Millisecond => {
+                as_primitive_array::<TimestampMillisecondType>(column).value_as_datetime(i)
+            }
+            TimeUnit::Microsecond => {
+                as_primitive_array::<TimestampMicrosecondType>(column).value_as_datetime(i)
+            }
+            TimeUnit::Nanosecond => {
+                as_primitive_array::<TimestampNanosecondType>(column).value_as_datetime(i)
+            }
+        }
+        .map_or(Cell::Empty, Cell::DateTime),
+        _ => Cell::Text(array_value_to_string(column, i)?),
+    };
+    Ok(cell)
+}
+
+fn write_record_batches_to_table(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
+    dest.write_all(pretty_format_batches(rbs)?.to_string().as_bytes())?;
+    dest.write_all(b"\n")?;
+    Ok(())
+}
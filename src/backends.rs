@@ -3,3 +3,9 @@ pub mod datafusion;
 
 #[cfg(feature = "duckdb")]
 pub mod duckdb;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
@@ -1,27 +1,39 @@
 use std::io::prelude::*;
+use std::sync::Arc;
+use std::{fs, io};
 
 use anyhow::{anyhow, Result};
 use log::{debug, error, info, warn};
 
 use datafusion::dataframe::DataFrame;
-use datafusion::datasource::listing::{ListingTable, ListingTableConfig};
+use datafusion::datasource::file_format::DEFAULT_SCHEMA_INFER_MAX_RECORD;
+use datafusion::datasource::MemTable;
+use datafusion::parquet as datafusion_parquet;
 use datafusion::prelude::*;
 
-// writer imports
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::ipc;
 use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::arrow::util::pretty::pretty_format_batches;
-use datafusion::arrow::{csv, json};
-use datafusion::parquet::arrow::arrow_writer;
 
-use crate::{get_dest_from_to, get_sql_from_query, OutputFormat, OutputWriter, SourcesType};
+use crate::arrow_io::{
+    self, get_csv_reader, get_json_reader, get_sheet_reader, get_stdin_reader, SchemaWithBatches,
+};
+use crate::database::DatabaseSpec;
+use crate::on_error::OnError;
+use crate::{
+    compression, encoding, get_sql_from_query, get_stdin_format, xlsx, OutputFormat, OutputOptions,
+    OutputWriter, SourcesType, TempFiles,
+};
 
 pub async fn query(
     query: &str,
     sources: &SourcesType,
     to: &str,
-    database: &str,
+    database: Option<&DatabaseSpec>,
     format: &OutputFormat,
     writer: &OutputWriter,
+    output_options: &OutputOptions,
 ) -> Result<()> {
     // compile the PRQL to SQL
     let sql = get_sql_from_query(query)?;
@@ -34,99 +46,196 @@ pub async fn query(
     let config = SessionConfig::new().with_information_schema(true);
     let ctx = SessionContext::with_config(config);
 
-    for (alias, filename) in sources.iter() {
-        if filename.ends_with("csv") {
-            ctx.register_csv(alias, filename, CsvReadOptions::new())
-                .await?;
+    // decompressed and transcoded copies of sources
+    let mut temp_paths = TempFiles::default();
+
+    for spec in sources.iter() {
+        let (alias, mut filename, csv_options) = (&spec.alias, &spec.source, &spec.csv);
+        if let Some(temp_path) = compression::decompress_to_temp_file(filename)? {
+            temp_paths.push(temp_path);
+            filename = temp_paths.last().unwrap();
+        }
+        if let Some(temp_path) = encoding::transcode_to_temp_file(filename, &spec.encoding)? {
+            temp_paths.push(temp_path);
+            filename = temp_paths.last().unwrap();
+        }
+        if let Some(stdin_format) = get_stdin_format(filename)? {
+            register_record_batches(&ctx, alias, get_stdin_reader(stdin_format, spec)?)?;
+        } else if let Some((path, sheet)) = xlsx::parse_source(filename) {
+            register_record_batches(&ctx, alias, get_sheet_reader(path, sheet)?)?;
+        } else if filename.ends_with("arrow") || filename.ends_with("feather") {
+            let reader = ipc::reader::FileReader::try_new(fs::File::open(filename)?, None)?;
+            let schema = reader.schema();
+            let rbs = reader.collect::<ArrowResult<Vec<RecordBatch>>>()?;
+            ctx.register_table(
+                alias.as_str(),
+                Arc::new(MemTable::try_new(schema, vec![rbs])?),
+            )?;
+        } else if filename.ends_with("csv") || filename.ends_with("tsv") {
+            // sources with a schema are read with the shared reader, which
+            // applies it to the inferred column types
+            if csv_options.is_simple() && spec.on_error == OnError::Fail && spec.schema.is_none() {
+                let infer_rows = spec.infer_rows.unwrap_or(DEFAULT_SCHEMA_INFER_MAX_RECORD);
+                let options = CsvReadOptions::new()
+                    .has_header(csv_options.header)
+                    .delimiter(csv_options.get_delimiter(filename))
+                    .schema_infer_max_records(infer_rows)
+                    .file_extension(if filename.ends_with("tsv") {
+                        ".tsv"
+                    } else {
+                        ".csv"
+                    });
+                ctx.register_csv(alias, filename, options).await?;
+            } else {
+                let reader = get_csv_reader(fs::File::open(filename)?, filename, spec)?;
+                register_record_batches(&ctx, alias, reader)?;
+            }
+        } else if filename.ends_with("json")
+            && (spec.on_error != OnError::Fail || spec.schema.is_some())
+        {
+            let reader = get_json_reader(fs::File::open(filename)?, spec)?;
+            register_record_batches(&ctx, alias, reader)?;
         } else if filename.ends_with("json") {
-            ctx.register_json(alias, filename, NdJsonReadOptions::default())
-                .await?;
+            let options = NdJsonReadOptions {
+                schema_infer_max_records: spec
+                    .infer_rows
+                    .unwrap_or(DEFAULT_SCHEMA_INFER_MAX_RECORD),
+                ..Default::default()
+            };
+            ctx.register_json(alias, filename, options).await?;
         } else if filename.ends_with("parquet") {
             ctx.register_parquet(alias, filename, ParquetReadOptions::default())
                 .await?;
         } else {
             unimplemented!("filename={filename:?}");
         }
+
+        if let Some(columns) = &spec.columns {
+            // replace the table with a projection of it
+            let columns: Vec<&str> = columns.iter().map(|c| c.as_str()).collect();
+            let df = ctx.table(alias.as_str())?.select_columns(&columns)?;
+            ctx.deregister_table(alias.as_str())?;
+            ctx.register_table(alias.as_str(), df)?;
+        }
     }
 
     // Run the query
     let df = ctx.sql(&sql).await?;
     //let rbs = df.collect().await?;
 
-    match writer {
-        OutputWriter::arrow => write_results_with_arrow(&df.collect().await?, to, format),
-        OutputWriter::backend => write_results_with_datafusion(&df, to, format).await,
-    }
+    let result = match writer {
+        OutputWriter::arrow => match df.collect().await {
+            Ok(rbs) => arrow_io::write_results_with_arrow(
+                &to_shared_batches(&rbs)?,
+                to,
+                format,
+                output_options,
+            ),
+            Err(e) => Err(e.into()),
+        },
+        OutputWriter::backend => {
+            write_results_with_datafusion(&df, to, format, output_options).await
+        }
+    };
+    result
+}
+
+fn register_record_batches(
+    ctx: &SessionContext,
+    alias: &str,
+    reader: SchemaWithBatches,
+) -> Result<()> {
+    let (schema, rbs) = from_shared_batches(reader)?;
+    let table = MemTable::try_new(schema, vec![rbs])?;
+    ctx.register_table(alias, Arc::new(table))?;
+    Ok(())
 }
 
 async fn write_results_with_datafusion(
     df: &DataFrame,
     to: &str,
     format: &OutputFormat,
+    output_options: &OutputOptions,
 ) -> Result<()> {
     // Write the results using the native datafusion writer
     match format {
         OutputFormat::csv => df.write_csv(to).await?,
         OutputFormat::json => df.write_json(to).await?,
-        OutputFormat::parquet => df.write_parquet(to, None).await?,
+        OutputFormat::parquet => {
+            let properties =
+                arrow_io::writer_properties!(datafusion_parquet, &output_options.parquet);
+            df.write_parquet(to, Some(properties)).await?
+        }
         OutputFormat::table => df.show().await?,
+        OutputFormat::tsv
+        | OutputFormat::arrow
+        | OutputFormat::arrows
+        | OutputFormat::markdown
+        | OutputFormat::html
+        | OutputFormat::latex
+        | OutputFormat::xlsx => {
+            return Err(anyhow!(
+                "The datafusion writer does not support format={format:?}. Use --writer=arrow."
+            ))
+        }
+        OutputFormat::database => {
+            return Err(anyhow!(
+                "The datafusion backend cannot write to a database."
+            ))
+        }
     }
 
     Ok(())
 }
 
-fn write_results_with_arrow(rbs: &[RecordBatch], to: &str, format: &OutputFormat) -> Result<()> {
-    let mut dest: Box<dyn Write> = get_dest_from_to(to)?;
-
-    match format {
-        OutputFormat::csv => write_record_batches_to_csv(rbs, &mut dest)?,
-        OutputFormat::json => write_record_batches_to_json(rbs, &mut dest)?,
-        OutputFormat::parquet => write_record_batches_to_parquet(rbs, &mut dest)?,
-        OutputFormat::table => write_record_batches_to_table(rbs, &mut dest)?,
-    }
-
-    Ok(())
-}
-
-fn write_record_batches_to_csv(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
+/// Returns the record batches of DataFusion's arrow crate for those read by
+/// the shared readers, which use the arrow crate of the other backends when
+/// they are enabled. Since the two are different versions of the crate, the
+/// batches are passed between them as an Arrow IPC stream.
+#[cfg(feature = "arrow")]
+fn from_shared_batches(
+    (schema, reader): SchemaWithBatches,
+) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let mut buf: Vec<u8> = Vec::new();
     {
-        let mut writer = csv::Writer::new(dest);
-        for rb in rbs {
-            writer.write(rb)?;
+        let mut writer = ::arrow::ipc::writer::StreamWriter::try_new(&mut buf, &schema)?;
+        for rb in reader {
+            writer.write(&rb?)?;
         }
+        writer.finish()?;
     }
-    Ok(())
+    let reader = ipc::reader::StreamReader::try_new(io::Cursor::new(buf), None)?;
+    let schema = reader.schema();
+    Ok((schema, reader.collect::<ArrowResult<Vec<RecordBatch>>>()?))
 }
 
-fn write_record_batches_to_json(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
-    {
-        // let mut writer = json::ArrayWriter::new(&mut buf);
-        let mut writer = json::LineDelimitedWriter::new(dest);
-        writer.write_batches(rbs)?;
-        writer.finish()?;
-    }
-    Ok(())
+#[cfg(not(feature = "arrow"))]
+fn from_shared_batches(
+    (schema, reader): SchemaWithBatches,
+) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    Ok((schema, reader.collect::<ArrowResult<Vec<RecordBatch>>>()?))
 }
 
-fn write_record_batches_to_parquet(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
+/// Returns the record batches for the shared writers (see
+/// `from_shared_batches`).
+#[cfg(feature = "arrow")]
+fn to_shared_batches(rbs: &[RecordBatch]) -> Result<Vec<::arrow::record_batch::RecordBatch>> {
     if rbs.is_empty() {
-        return Ok(());
+        return Ok(Vec::new());
     }
-
-    let schema = rbs[0].schema();
+    let mut buf: Vec<u8> = Vec::new();
     {
-        let mut writer = arrow_writer::ArrowWriter::try_new(dest, schema, None)?;
-
+        let mut writer = ipc::writer::StreamWriter::try_new(&mut buf, &rbs[0].schema())?;
         for rb in rbs {
             writer.write(rb)?;
         }
-        writer.close()?;
+        writer.finish()?;
     }
-    Ok(())
+    let reader = ::arrow::ipc::reader::StreamReader::try_new(io::Cursor::new(buf), None)?;
+    Ok(reader.collect::<::arrow::error::Result<Vec<::arrow::record_batch::RecordBatch>>>()?)
 }
 
-fn write_record_batches_to_table(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
-    dest.write(pretty_format_batches(rbs)?.to_string().as_bytes());
-    dest.write(b"\n");
-    Ok(())
+#[cfg(not(feature = "arrow"))]
+fn to_shared_batches(rbs: &[RecordBatch]) -> Result<Vec<RecordBatch>> {
+    Ok(rbs.to_vec())
 }
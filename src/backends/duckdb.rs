@@ -1,43 +1,108 @@
 use std::io::prelude::*;
+use std::{fs, io};
 
 use anyhow::{anyhow, Result};
 use log::{debug, error, info, warn};
 
+use arrow::csv;
+use arrow::datatypes::*;
+use arrow::ipc;
 use arrow::record_batch::RecordBatch;
-use arrow::util::pretty::pretty_format_batches;
-use arrow::{csv, json};
 use parquet::arrow::arrow_writer;
 
-use chrono::{DateTime, Utc};
-use duckdb::{
-    types::{FromSql, ValueRef},
-    Connection,
-};
-use regex::Regex;
+use camino::Utf8Path;
+use duckdb::{AccessMode, Config, Connection};
 
-use crate::{get_dest_from_to, get_sql_from_query, OutputFormat, OutputWriter, SourcesType};
+use crate::arrow_io::{
+    get_csv_reader, get_json_reader, get_schema_with_overrides, get_sheet_reader, get_stdin_reader,
+    write_record_batches_to_parquet, write_results_with_arrow, SchemaWithBatches,
+};
+use crate::database::{self, AttachedDatabases, DatabaseScheme, DatabaseSpec};
+use crate::delimited::{self, CsvOptions};
+use crate::on_error::OnError;
+use crate::{
+    compression, encoding, get_dest_from_to, get_stdin_format, get_temp_path, xlsx, OutputFormat,
+    OutputOptions, OutputWriter, ParquetOptions, SourceSpec, SourcesType, TempFiles,
+};
 
+#[allow(clippy::too_many_arguments)]
 pub fn query(
     query: &str,
     sources: &SourcesType,
     to: &str,
-    database: &str,
+    database: Option<&DatabaseSpec>,
+    attached: &AttachedDatabases,
     format: &OutputFormat,
     writer: &OutputWriter,
+    output_options: &OutputOptions,
 ) -> Result<()> {
+    let extension_dir = output_options.duckdb_extension_dir.as_deref();
+    // staging files for sources that DuckDB cannot read directly, as well as
+    // decompressed and transcoded copies of sources
+    let mut staging_paths = TempFiles::default();
+    // whether any source, including the staged ones, is read from parquet
+    let mut reads_parquet = false;
+
     let sql_query = if query.starts_with("prql ") {
         let mut stmts = parse(query)?;
 
         // prepend CTEs for each of the sources
-        for (name, source) in sources.iter() {
-            let source_sql = if source.ends_with(".csv") {
-                format!("read_csv_auto('{source}')")
+        for spec in sources.iter() {
+            let (name, mut source) = (&spec.alias, &spec.source);
+            if let Some(temp_path) = compression::decompress_to_temp_file(source)? {
+                staging_paths.push(temp_path);
+                source = staging_paths.last().unwrap();
+            }
+            if let Some(temp_path) = encoding::transcode_to_temp_file(source, &spec.encoding)? {
+                staging_paths.push(temp_path);
+                source = staging_paths.last().unwrap();
+            }
+            let source_sql = if let Some(stdin_format) = get_stdin_format(source)? {
+                let staging_path = stage_record_batches(
+                    name,
+                    get_stdin_reader(stdin_format, spec)?,
+                    &mut staging_paths,
+                )?;
+                format!("read_parquet('{staging_path}')")
+            } else if let Some((path, sheet)) = xlsx::parse_source(source) {
+                let staging_path =
+                    stage_record_batches(name, get_sheet_reader(path, sheet)?, &mut staging_paths)?;
+                format!("read_parquet('{staging_path}')")
+            } else if source.ends_with(".arrow") || source.ends_with(".feather") {
+                let reader = ipc::reader::FileReader::try_new(fs::File::open(source)?, None)?;
+                let staging_path = stage_record_batches(
+                    name,
+                    (reader.schema(), Box::new(reader)),
+                    &mut staging_paths,
+                )?;
+                format!("read_parquet('{staging_path}')")
+            } else if (source.ends_with(".csv") || source.ends_with(".tsv"))
+                && spec.on_error != OnError::Fail
+            {
+                // the malformed rows are skipped while staging the source
+                let reader = get_csv_reader(fs::File::open(source)?, source, spec)?;
+                let staging_path = stage_record_batches(name, reader, &mut staging_paths)?;
+                format!("read_parquet('{staging_path}')")
+            } else if source.ends_with(".csv") || source.ends_with(".tsv") {
+                get_read_csv_sql(source, spec)?
+            } else if source.ends_with(".json") {
+                // json sources are staged, so that their schema and
+                // infer_rows options are applied as for the other backends
+                let reader = get_json_reader(fs::File::open(source)?, spec)?;
+                let staging_path = stage_record_batches(name, reader, &mut staging_paths)?;
+                format!("read_parquet('{staging_path}')")
             } else if source.ends_with(".parquet") {
                 format!("read_parquet('{source}')")
-            } else if database.starts_with("postgres") {
+            } else if let Some(
+                database @ DatabaseSpec {
+                    scheme: DatabaseScheme::Postgres,
+                    ..
+                },
+            ) = database
+            {
                 let mut parts: Vec<&str> = source.split('.').collect();
                 if parts.len() == 1 {
-                    parts.insert(0, "public");
+                    parts.insert(0, database.current_schema.as_deref().unwrap_or("public"));
                 }
                 let table = parts
                     .pop()
@@ -45,14 +110,24 @@ pub fn query(
                 let schema = parts
                     .pop()
                     .ok_or(anyhow!("Couldn't extract schema name from {source}."))?;
-                format!("postgres_scan('{database}', '{schema}', '{table}')")
+                let uri = database.get_postgres_uri();
+                format!("postgres_scan('{uri}', '{schema}', '{table}')")
             } else {
                 format!("'{source}'")
             };
 
+            reads_parquet |= source_sql.starts_with("read_parquet(");
+
+            let columns = spec.columns.as_ref().map_or(String::from("*"), |columns| {
+                columns
+                    .iter()
+                    .map(|c| format!("\"{}\"", c.replace('"', "\"\"")))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            });
             let mut relation_decl = parse(&format!(
                 r#"
-                let {name} = s"SELECT * FROM {source_sql}"
+                let {name} = s"""SELECT {columns} FROM {source_sql}"""
                 "#
             ))?;
 
@@ -64,128 +139,389 @@ pub fn query(
             .and_then(|rq| prql_compiler::rq_to_sql(rq, &prql_compiler::Options::default()))
             .map_err(|e| anyhow!(e))?
     } else {
+        // the sources of a SQL query are the files and table functions it reads
+        let sql = query.to_lowercase();
+        reads_parquet = ["read_parquet(", "parquet_scan(", ".parquet'"]
+            .iter()
+            .any(|reference| sql.contains(reference));
         query.to_string()
     };
-    debug!("sql_query = {sql_query}");
+    debug!("sql_query = {}", database::redact(&sql_query));
 
     // prepare the connection and statement
-    let conn = if database == "" {
-        debug!("Opening in-memory DuckDB database");
-        Connection::open_in_memory()?
-    } else if database.starts_with("sqlite://") {
-        let con = Connection::open_in_memory()?;
-        // Install and load the sqlite_scanner extension
-        let load_extension = "INSTALL sqlite_scanner; LOAD sqlite_scanner;";
-        con.execute_batch(load_extension)?;
-        let dbpath = database.strip_prefix("sqlite://").map_or(database, |p| p);
-        let attach_sql = format!("CALL sqlite_attach('{dbpath}')");
-        con.execute_batch(&attach_sql)?;
-        con
-    } else if database.starts_with("postgres") {
-        let con = Connection::open_in_memory()?;
-        // Check if a schema was specified
-        let re = Regex::new(r"^(?P<uri>[^?]+)(?P<schema>\?currentSchema=.+)?$")?;
-        let caps = re
-            .captures(database)
-            .ok_or(anyhow!("Couldn't match regex!"))?;
-        let uri = caps
-            .name("uri")
-            .ok_or(anyhow!("Couldn't extract URI!"))?
-            .as_str();
-        debug!("uri={:?}", uri);
-        let schema_param = caps
-            .name("schema")
-            .map_or("?currentSchema=public", |p| p.as_str());
-        let schema = schema_param.split("=").last().map_or("public", |p| p);
-        debug!("schema={:?}", schema);
-        // Install and load the postgres_scanner extension
-        let load_extension = "INSTALL postgres_scanner; LOAD postgres_scanner;";
-        con.execute_batch(load_extension)?;
-        let attach_sql = format!("CALL postgres_attach('{uri}', source_schema='{schema}')");
-        debug!("attach_sql={:?}", attach_sql);
-        con.execute_batch(&attach_sql)?;
-        con
+    let config = if database.is_some_and(|database| database.read_only) {
+        Config::default().access_mode(AccessMode::ReadOnly)?
     } else {
-        let dbpath = database.strip_prefix("duckdb://").map_or(database, |p| p);
-        debug!("Opening DuckDB database: dbpath={:?}", dbpath);
-        Connection::open(dbpath)?
+        Config::default()
     };
+    let conn = match database {
+        None => {
+            debug!("Opening in-memory DuckDB database");
+            Connection::open_in_memory()?
+        }
+        Some(database) => match database.scheme {
+            DatabaseScheme::Sqlite => {
+                let con = Connection::open_in_memory()?;
+                load_extension(&con, "sqlite_scanner", extension_dir)?;
+                let attach_sql = format!("CALL sqlite_attach('{}')", database.path);
+                con.execute_batch(&attach_sql)?;
+                con
+            }
+            DatabaseScheme::Postgres => {
+                let con = Connection::open_in_memory()?;
+                let uri = database.get_postgres_uri();
+                let schema = database.current_schema.as_deref().unwrap_or("public");
+                debug!("schema={:?}", schema);
+                load_extension(&con, "postgres_scanner", extension_dir)?;
+                let attach_sql = format!("CALL postgres_attach('{uri}', source_schema='{schema}')");
+                con.execute_batch(&attach_sql)?;
+                con
+            }
+            DatabaseScheme::DuckDb => {
+                debug!("Opening DuckDB database: dbpath={:?}", database.path);
+                Connection::open_with_flags(&database.path, config)?
+            }
+        },
+    };
+
+    // Load the parquet extension if the sources are read from parquet files
+    // or the results are written to one by DuckDB
+    if reads_parquet
+        || (matches!(writer, OutputWriter::backend) && *format == OutputFormat::parquet)
+    {
+        load_extension(&conn, "parquet", extension_dir)?;
+    }
 
-    // Install and load the parquet extension
-    // FIXME: Be smarter about this and only do it where required
-    let load_parquet_extension = "INSTALL parquet; LOAD parquet;";
-    conn.execute_batch(load_parquet_extension)?;
+    // The attached databases are only set up for this query, within a
+    // transaction that is rolled back so nothing is left in a DuckDB database
+    if !attached.is_empty() {
+        if let Some(database) = database
+            .filter(|database| database.scheme == DatabaseScheme::DuckDb && database.read_only)
+        {
+            return Err(anyhow!(
+                "Attaching databases to the DuckDB database {:?} requires --allow-writes, since the views of their tables are created in it (and rolled back after the query).",
+                database.path
+            ));
+        }
+        conn.execute_batch("BEGIN TRANSACTION;")?;
+        attach_databases(&conn, &sql_query, attached, extension_dir)?;
+    }
 
     // Execute the query
-    let mut stmt = conn.prepare(&sql_query)?;
-    let rbs = stmt.query_arrow([])?.collect::<Vec<RecordBatch>>();
+    let rbs = {
+        let mut stmt = conn.prepare(&sql_query)?;
+        stmt.query_arrow([])?.collect::<Vec<RecordBatch>>()
+    };
+    if !attached.is_empty() {
+        conn.execute_batch("ROLLBACK;")?;
+    }
+    // close the database, which may also be the one the results are written to
+    drop(conn);
+    drop(staging_paths);
+
+    if *format == OutputFormat::database {
+        return write_results_with_arrow(&rbs, to, format, output_options);
+    }
 
     match writer {
-        OutputWriter::arrow => write_results_with_arrow(&rbs, to, format),
+        OutputWriter::arrow => write_results_with_arrow(&rbs, to, format, output_options),
         OutputWriter::backend => write_results_with_duckdb(&rbs, to, format),
     }
 }
 
-fn parse(query: &str) -> Result<Vec<prql_compiler::ast::pl::Stmt>> {
-    prql_compiler::prql_to_pl(query).map_err(|e| anyhow!(e))
+/// Creates a schema for each attached database with views of its tables,
+/// since DuckDB can't attach other databases itself. Only the tables the
+/// query references as `<alias>.<table>` are scanned from Postgres.
+fn attach_databases(
+    conn: &Connection,
+    sql: &str,
+    attached: &AttachedDatabases,
+    extension_dir: Option<&str>,
+) -> Result<()> {
+    for (alias, database) in attached.iter() {
+        let (extension, tables) = match database.scheme {
+            DatabaseScheme::Sqlite => ("sqlite_scanner", get_sqlite_tables(&database.path)?),
+            DatabaseScheme::Postgres => ("postgres_scanner", get_table_references(sql, alias)),
+            DatabaseScheme::DuckDb => {
+                return Err(anyhow!(
+                    "DuckDB can't attach another DuckDB database, so {alias:?} has to be the --database without an alias."
+                ))
+            }
+        };
+        debug!("Attaching {alias:?} with the tables {tables:?}");
+        load_extension(conn, extension, extension_dir)?;
+        conn.execute_batch(&format!("CREATE SCHEMA \"{alias}\";"))?;
+        for table in tables.iter() {
+            let scan_sql = match database.scheme {
+                DatabaseScheme::Postgres => {
+                    let uri = database.get_postgres_uri();
+                    let schema = database.current_schema.as_deref().unwrap_or("public");
+                    format!("postgres_scan('{uri}', '{schema}', '{table}')")
+                }
+                _ => format!("sqlite_scan('{}', '{table}')", database.path),
+            };
+            conn.execute_batch(&format!(
+                "CREATE VIEW \"{alias}\".\"{}\" AS SELECT * FROM {scan_sql};",
+                table.replace('"', "\"\"")
+            ))?;
+        }
+    }
+    Ok(())
 }
 
-fn write_results_with_duckdb(rbs: &[RecordBatch], to: &str, format: &OutputFormat) -> Result<()> {
-    unimplemented!("write_results_with_duckdb");
+fn get_sqlite_tables(path: &str) -> Result<Vec<String>> {
+    if !Utf8Path::new(path).exists() {
+        return Err(anyhow!("The database {path:?} does not exist."));
+    }
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt =
+        conn.prepare("SELECT name FROM sqlite_master WHERE type IN ('table', 'view')")?;
+    let tables = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(tables)
 }
 
-fn write_results_with_arrow(rbs: &[RecordBatch], to: &str, format: &OutputFormat) -> Result<()> {
-    let mut dest: Box<dyn Write> = get_dest_from_to(to)?;
-
-    match format {
-        OutputFormat::csv => write_record_batches_to_csv(rbs, &mut dest)?,
-        OutputFormat::json => write_record_batches_to_json(rbs, &mut dest)?,
-        OutputFormat::parquet => write_record_batches_to_parquet(rbs, &mut dest)?,
-        OutputFormat::table => write_record_batches_to_table(rbs, &mut dest)?,
+/// Returns the tables referenced in the SQL as `<alias>.<table>`, where
+/// either name may be quoted.
+fn get_table_references(sql: &str, alias: &str) -> Vec<String> {
+    let is_identifier_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tables: Vec<String> = Vec::new();
+    for prefix in [format!("{alias}."), format!("\"{alias}\".")] {
+        for (start, _) in sql.match_indices(&prefix) {
+            if sql[..start].ends_with(|c: char| is_identifier_char(c) || c == '"' || c == '.') {
+                continue;
+            }
+            let rest = &sql[start + prefix.len()..];
+            let table = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split('"').next().unwrap_or(""),
+                None => rest
+                    .split(|c: char| !is_identifier_char(c))
+                    .next()
+                    .unwrap_or(""),
+            };
+            if !table.is_empty() && !tables.iter().any(|t| t == table) {
+                tables.push(table.to_string());
+            }
+        }
     }
+    tables
+}
 
-    Ok(())
+/// Loads a DuckDB extension from the extension directory if one is given.
+/// Otherwise the extension is installed, which requires network access, only
+/// if it can't already be loaded.
+fn load_extension(conn: &Connection, name: &str, extension_dir: Option<&str>) -> Result<()> {
+    if let Some(extension_dir) = extension_dir {
+        let path = Utf8Path::new(extension_dir).join(format!("{name}.duckdb_extension"));
+        if !path.exists() {
+            return Err(anyhow!(
+                "The DuckDB extension {name:?} was not found at {path:?}."
+            ));
+        }
+        debug!("Loading DuckDB extension {path:?}");
+        conn.execute_batch(&format!("LOAD '{path}';"))?;
+        return Ok(());
+    }
+    if conn.execute_batch(&format!("LOAD {name};")).is_ok() {
+        return Ok(());
+    }
+    debug!("Installing DuckDB extension {name:?}");
+    conn.execute_batch(&format!("INSTALL {name}; LOAD {name};"))
+        .map_err(|e| {
+            anyhow!(
+                "Could not install the DuckDB extension {name:?}, which requires network access: {e}. Without network access, use --duckdb-extension-dir with a directory containing {name}.duckdb_extension."
+            )
+        })
 }
 
-fn write_record_batches_to_csv(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
-    {
-        let mut writer = csv::Writer::new(dest);
-        for rb in rbs {
-            writer.write(rb)?;
+/// Returns the call of read_csv_auto for a csv source or, when a schema is
+/// given, of read_csv with the column types. The columns are inferred with
+/// arrow, since DuckDB requires all of them to be listed.
+fn get_read_csv_sql(source: &str, spec: &SourceSpec) -> Result<String> {
+    let csv_options = &spec.csv;
+    let parameters = get_read_csv_parameters(source, csv_options);
+    let schema_spec = match &spec.schema {
+        Some(schema_spec) => schema_spec,
+        None => {
+            let sample_size = spec
+                .infer_rows
+                .map_or(String::new(), |n| format!(", sample_size={n}"));
+            return Ok(format!(
+                "read_csv_auto('{source}'{parameters}{sample_size})"
+            ));
         }
+    };
+    let buf = delimited::normalise(fs::File::open(source)?, source, csv_options)?;
+    let (inferred, _) = csv::reader::infer_file_schema(
+        io::Cursor::new(buf),
+        b',',
+        spec.infer_rows,
+        csv_options.header,
+    )?;
+    let schema = get_schema_with_overrides(inferred, schema_spec)?;
+    // braces are doubled since the SQL ends up in a PRQL s-string
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            format!(
+                "'{}': '{}'",
+                field.name().replace('\'', "''"),
+                get_duckdb_type(field.data_type())
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    let header = if csv_options.header {
+        ", header=true"
+    } else {
+        ""
+    };
+    Ok(format!(
+        "read_csv('{source}', columns={{{{{columns}}}}}{header}{parameters})"
+    ))
+}
+
+fn get_duckdb_type(data_type: &DataType) -> String {
+    match data_type {
+        DataType::Boolean => String::from("BOOLEAN"),
+        DataType::Int32 => String::from("INTEGER"),
+        DataType::Int64 => String::from("BIGINT"),
+        DataType::Float32 => String::from("FLOAT"),
+        DataType::Float64 => String::from("DOUBLE"),
+        DataType::Decimal128(precision, scale) => format!("DECIMAL({precision},{scale})"),
+        DataType::Date32 => String::from("DATE"),
+        DataType::Timestamp(_, _) => String::from("TIMESTAMP"),
+        _ => String::from("VARCHAR"),
     }
-    Ok(())
 }
 
-fn write_record_batches_to_json(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
-    {
-        // let mut writer = json::ArrayWriter::new(&mut buf);
-        let mut writer = json::LineDelimitedWriter::new(dest);
-        writer.write_batches(&rbs)?;
-        writer.finish()?;
+/// Returns the read_csv_auto parameters for the csv options that differ
+/// from DuckDB's defaults
+fn get_read_csv_parameters(source: &str, csv_options: &CsvOptions) -> String {
+    let literal = |c: u8| format!("'{}'", (c as char).to_string().replace('\'', "''"));
+    let mut parameters: Vec<String> = Vec::new();
+    let delimiter = csv_options.get_delimiter(source);
+    if delimiter != b',' {
+        parameters.push(format!("delim={}", literal(delimiter)));
     }
-    Ok(())
+    if csv_options.quote != b'"' {
+        parameters.push(format!("quote={}", literal(csv_options.quote)));
+    }
+    if let Some(escape) = csv_options.escape {
+        parameters.push(format!("escape={}", literal(escape)));
+    }
+    if !csv_options.header {
+        parameters.push(String::from("header=false"));
+    }
+    if let Some(null_value) = &csv_options.null_value {
+        parameters.push(format!("nullstr='{}'", null_value.replace('\'', "''")));
+    }
+    if csv_options.skip_rows > 0 {
+        parameters.push(format!("skip={}", csv_options.skip_rows));
+    }
+    parameters
+        .iter()
+        .map(|parameter| format!(", {parameter}"))
+        .collect()
 }
 
-fn write_record_batches_to_parquet(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
-    if rbs.is_empty() {
-        return Ok(());
+/// Writes record batches that DuckDB cannot read directly (e.g. Arrow IPC
+/// files or sources from stdin) to a staging parquet file, which is added to
+/// the staging files to remove after the query.
+fn stage_record_batches(
+    name: &str,
+    (schema, reader): SchemaWithBatches,
+    staging_paths: &mut TempFiles,
+) -> Result<String> {
+    let staging_path = get_temp_path(&format!("{name}.parquet"));
+    debug!("staging_path={staging_path:?}");
+    staging_paths.push(staging_path.clone());
+    let mut writer =
+        arrow_writer::ArrowWriter::try_new(fs::File::create(&staging_path)?, schema, None)?;
+    for rb in reader {
+        writer.write(&rb?)?;
     }
+    writer.close()?;
 
-    let schema = rbs[0].schema();
-    {
-        let mut writer = arrow_writer::ArrowWriter::try_new(dest, schema, None)?;
+    Ok(staging_path)
+}
 
-        for rb in rbs {
-            writer.write(rb)?;
-        }
-        writer.close()?;
+fn parse(query: &str) -> Result<Vec<prql_compiler::ast::pl::Stmt>> {
+    prql_compiler::prql_to_pl(query).map_err(|e| anyhow!(e))
+}
+
+fn write_results_with_duckdb(rbs: &[RecordBatch], to: &str, format: &OutputFormat) -> Result<()> {
+    unimplemented!("write_results_with_duckdb");
+}
+
+pub fn write_record_batches_to_duckdb(
+    rbs: &[RecordBatch],
+    dbpath: &str,
+    table: &str,
+    append: bool,
+    extension_dir: Option<&str>,
+) -> Result<()> {
+    // Stage the results in a parquet file so that DuckDB derives the table
+    // schema from the Arrow schema itself.
+    let mut staging_paths = TempFiles::default();
+    let staging_path = get_temp_path(&format!("{table}.parquet"));
+    debug!("staging_path={staging_path:?}");
+    staging_paths.push(staging_path.clone());
+    {
+        let mut dest = get_dest_from_to(&staging_path, false)?;
+        write_record_batches_to_parquet(rbs, &mut dest, &ParquetOptions::default())?;
+        dest.finish()?;
     }
+
+    let source_sql = format!("SELECT * FROM read_parquet('{staging_path}')");
+    let quoted_table = database::quote_table_name(table);
+
+    debug!("Opening DuckDB database: dbpath={:?}", dbpath);
+    let conn = Connection::open(dbpath)?;
+    load_extension(&conn, "parquet", extension_dir)?;
+    let write_sql = if append {
+        let schema = rbs[0].schema();
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {quoted_table} AS {source_sql} LIMIT 0;"
+        ))?;
+        check_duckdb_append_columns(&conn, table, &schema)?;
+        // the columns are named, since those of the table may be in
+        // another order
+        let columns: Vec<String> = schema
+            .fields()
+            .iter()
+            .map(|f| format!("\"{}\"", f.name().replace('"', "\"\"")))
+            .collect();
+        format!(
+            "INSERT INTO {quoted_table} ({}) {source_sql};",
+            columns.join(", ")
+        )
+    } else {
+        format!("CREATE OR REPLACE TABLE {quoted_table} AS {source_sql};")
+    };
+    debug!("write_sql={write_sql:?}");
+    conn.execute_batch(&write_sql)?;
+
     Ok(())
 }
 
-fn write_record_batches_to_table(rbs: &[RecordBatch], dest: &mut dyn Write) -> Result<()> {
-    dest.write(pretty_format_batches(rbs)?.to_string().as_bytes());
-    dest.write(b"\n");
+/// Checks the columns of an existing table against those of the results
+/// about to be appended to it, in any order.
+fn check_duckdb_append_columns(conn: &Connection, table: &str, schema: &Schema) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE {}", database::quote_table_name(table)))?;
+    let mut existing = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<duckdb::Result<Vec<String>>>()?;
+    let mut columns: Vec<&String> = schema.fields().iter().map(|f| f.name()).collect();
+    existing.sort();
+    columns.sort();
+    if existing.iter().ne(columns.iter().copied()) {
+        return Err(anyhow!(
+            "Cannot append to the table {table}: its columns {existing:?} do not match {columns:?}."
+        ));
+    }
     Ok(())
 }
@@ -0,0 +1,436 @@
+//! A backend that pushes queries down to Postgres with the postgres client,
+//! rather than scanning its tables with the postgres_scanner extension of
+//! DuckDB, so that views and tables of any schema can be queried. All the
+//! sources have to be tables (or views) of the database. The results are
+//! written with the shared Arrow writers, which in turn write to Postgres
+//! tables with COPY.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use arrow::array::{
+    as_generic_binary_array, Array, ArrayRef, BooleanArray, Date32Array, Float64Array, Int32Array,
+    Int64Array, StringArray, TimestampMicrosecondArray,
+};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use chrono::{NaiveDate, NaiveDateTime};
+use postgres::types::Type;
+use postgres::{Client, NoTls, SimpleQueryMessage, Transaction};
+use prql_compiler::sql::Dialect;
+use prql_compiler::{Options, Target};
+
+use crate::arrow_io::write_results_with_arrow;
+use crate::database::{self, DatabaseScheme, DatabaseSpec};
+use crate::{is_database_table, OutputFormat, OutputOptions, OutputWriter, SourcesType};
+
+pub fn query(
+    query: &str,
+    sources: &SourcesType,
+    to: &str,
+    database: Option<&DatabaseSpec>,
+    format: &OutputFormat,
+    writer: &OutputWriter,
+    output_options: &OutputOptions,
+) -> Result<()> {
+    if let OutputWriter::backend = writer {
+        return Err(anyhow!(
+            "The postgres backend has no writer of its own. Use --writer arrow."
+        ));
+    }
+    let database = match database {
+        Some(database) if database.scheme == DatabaseScheme::Postgres => database,
+        Some(database) => {
+            return Err(anyhow!(
+                "The postgres backend can only query postgresql databases, not {} ones.",
+                database.scheme
+            ))
+        }
+        None => {
+            return Err(anyhow!(
+                "The postgres backend needs a postgresql:// database given with --database."
+            ))
+        }
+    };
+    if let Some(spec) = sources.iter().find(|spec| !is_database_table(&spec.source)) {
+        return Err(anyhow!(
+            "The postgres backend can only query tables of the database, not {:?}. Use --backend duckdb to join files with the tables of the database.",
+            spec.source
+        ));
+    }
+
+    let sql = if query.starts_with("prql ") {
+        let mut stmts = prql_compiler::prql_to_pl(query).map_err(|e| anyhow!(e))?;
+
+        // declare the sources that are aliased or projected
+        for spec in sources.iter() {
+            if spec.alias == spec.source && spec.columns.is_none() {
+                continue;
+            }
+            let columns = spec.columns.as_ref().map_or(String::from("*"), |columns| {
+                columns
+                    .iter()
+                    .map(|c| format!("\"{}\"", c.replace('"', "\"\"")))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            });
+            let mut relation_decl = prql_compiler::prql_to_pl(&format!(
+                r#"
+                let {} = s"""SELECT {columns} FROM {}"""
+                "#,
+                spec.alias, spec.source
+            ))
+            .map_err(|e| anyhow!(e))?;
+            stmts.insert(1, relation_decl.remove(0));
+        }
+
+        let options = Options {
+            target: Target::Sql(Some(Dialect::PostgreSql)),
+            ..Options::default()
+        };
+        Ok(stmts)
+            .and_then(prql_compiler::pl_to_rq)
+            .and_then(|rq| prql_compiler::rq_to_sql(rq, &options))
+            .map_err(|e| anyhow!(e))?
+    } else {
+        query.to_string()
+    };
+    debug!("sql = {sql:?}");
+
+    let mut client = connect(database)?;
+    let rb = get_record_batch(&mut client, &sql)?;
+    write_results_with_arrow(&[rb], to, format, output_options)
+}
+
+/// Connects to the database, looking unqualified tables up in its current
+/// schema and refusing writes if it's read-only.
+fn connect(database: &DatabaseSpec) -> Result<Client> {
+    let uri = database.get_postgres_uri();
+    debug!("Connecting to Postgres database {:?}", database.path);
+    let mut client = Client::connect(&uri, NoTls)?;
+    if let Some(schema) = &database.current_schema {
+        client.batch_execute(&format!(
+            "SET search_path TO \"{}\"",
+            schema.replace('"', "\"\"")
+        ))?;
+    }
+    if database.read_only {
+        client.batch_execute("SET default_transaction_read_only = on")?;
+    }
+    Ok(client)
+}
+
+/// Runs the query and converts its rows into a record batch. The values are
+/// received as text and parsed according to the type of their column, and
+/// columns of other types (e.g. numeric or json) are kept as text.
+fn get_record_batch(client: &mut Client, sql: &str) -> Result<RecordBatch> {
+    let stmt = client.prepare(sql)?;
+    let mut columns: Vec<Vec<Option<String>>> = vec![Vec::new(); stmt.columns().len()];
+    for message in client.simple_query(sql)? {
+        if let SimpleQueryMessage::Row(row) = message {
+            for (i, column) in columns.iter_mut().enumerate() {
+                column.push(row.get(i).map(String::from));
+            }
+        }
+    }
+
+    let arrays: Vec<ArrayRef> = stmt
+        .columns()
+        .iter()
+        .zip(columns.iter())
+        .map(|(column, values)| get_array(column.type_(), values))
+        .collect();
+    let fields: Vec<Field> = stmt
+        .columns()
+        .iter()
+        .zip(arrays.iter())
+        .map(|(column, array)| Field::new(column.name(), array.data_type().clone(), true))
+        .collect();
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+}
+
+/// Returns the values as an array of the type of the column, or as text if
+/// any of them can't be parsed (e.g. `infinity` dates).
+fn get_array(pg_type: &Type, values: &[Option<String>]) -> ArrayRef {
+    fn parse_all<T>(
+        values: &[Option<String>],
+        f: impl Fn(&str) -> Option<T>,
+    ) -> Option<Vec<Option<T>>> {
+        values
+            .iter()
+            .map(|value| match value {
+                Some(value) => f(value).map(Some),
+                None => Some(None),
+            })
+            .collect()
+    }
+
+    let array: Option<ArrayRef> = match *pg_type {
+        Type::BOOL => parse_all(values, |s| match s {
+            "t" => Some(true),
+            "f" => Some(false),
+            _ => None,
+        })
+        .map(|values| Arc::new(BooleanArray::from(values)) as ArrayRef),
+        Type::INT2 | Type::INT4 => parse_all(values, |s| s.parse::<i32>().ok())
+            .map(|values| Arc::new(Int32Array::from(values)) as ArrayRef),
+        Type::INT8 | Type::OID => parse_all(values, |s| s.parse::<i64>().ok())
+            .map(|values| Arc::new(Int64Array::from(values)) as ArrayRef),
+        Type::FLOAT4 | Type::FLOAT8 => parse_all(values, |s| s.parse::<f64>().ok())
+            .map(|values| Arc::new(Float64Array::from(values)) as ArrayRef),
+        Type::DATE => parse_all(values, |s| {
+            let epoch = NaiveDate::from_ymd(1970, 1, 1);
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .map(|date| (date - epoch).num_days() as i32)
+        })
+        .map(|values| Arc::new(Date32Array::from(values)) as ArrayRef),
+        Type::TIMESTAMP => parse_all(values, |s| {
+            NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f")
+                .ok()
+                .map(|datetime| datetime.timestamp_micros())
+        })
+        .map(|values| Arc::new(TimestampMicrosecondArray::from(values)) as ArrayRef),
+        _ => None,
+    };
+    array.unwrap_or_else(|| {
+        Arc::new(
+            values
+                .iter()
+                .map(|value| value.as_deref())
+                .collect::<StringArray>(),
+        )
+    })
+}
+
+/// Writes the record batches to a table of a Postgres database with COPY,
+/// creating the table from the Arrow schema if it doesn't exist (or isn't
+/// appended to).
+pub fn write_record_batches_to_postgres(
+    rbs: &[RecordBatch],
+    database: &DatabaseSpec,
+    table: &str,
+    append: bool,
+) -> Result<()> {
+    let columns: Vec<String> = rbs[0]
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| {
+            format!(
+                "\"{}\" {}",
+                f.name().replace('"', "\"\""),
+                postgres_type(f.data_type())
+            )
+        })
+        .collect();
+
+    let quoted_table = database::quote_table_name(table);
+
+    debug!("Connecting to Postgres database {:?}", database.path);
+    let mut client = Client::connect(&database.get_postgres_uri(), NoTls)?;
+    let mut tx = client.transaction()?;
+    if let Some(schema) = &database.current_schema {
+        tx.batch_execute(&format!(
+            "SET LOCAL search_path TO \"{}\"",
+            schema.replace('"', "\"\"")
+        ))?;
+    }
+    // timestamps with a time zone are written in UTC
+    tx.batch_execute("SET LOCAL TIME ZONE 'UTC'")?;
+    if append {
+        check_postgres_append_columns(&mut tx, table, &rbs[0].schema())?;
+    } else {
+        tx.batch_execute(&format!("DROP TABLE IF EXISTS {quoted_table};"))?;
+    }
+    tx.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {quoted_table} ({});",
+        columns.join(", ")
+    ))?;
+
+    // the columns are named, since those of the table may be in another order
+    let names: Vec<String> = rbs[0]
+        .schema()
+        .fields()
+        .iter()
+        .map(|f| format!("\"{}\"", f.name().replace('"', "\"\"")))
+        .collect();
+    let mut writer = tx.copy_in(&format!(
+        "COPY {quoted_table} ({}) FROM STDIN (FORMAT csv)",
+        names.join(", ")
+    ))?;
+    for rb in rbs {
+        let mut buf: Vec<u8> = Vec::new();
+        for row in 0..rb.num_rows() {
+            let values = rb
+                .columns()
+                .iter()
+                .map(|array| get_copy_value(array, row))
+                .collect::<Result<Vec<String>>>()?;
+            buf.extend_from_slice(values.join(",").as_bytes());
+            buf.push(b'\n');
+        }
+        writer.write_all(&buf)?;
+    }
+    writer.finish()?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Checks the columns of an existing table against those of the results
+/// about to be appended to it, in any order.
+fn check_postgres_append_columns(tx: &mut Transaction, table: &str, schema: &Schema) -> Result<()> {
+    // unqualified tables are looked up in the current schema
+    let (table_schema, table_name) = match table.rsplit_once('.') {
+        Some((table_schema, table_name)) => (Some(table_schema), table_name),
+        None => (None, table),
+    };
+    let mut existing: Vec<String> = tx
+        .query(
+            "SELECT column_name::text FROM information_schema.columns
+            WHERE table_schema::text = coalesce($1::text, current_schema())
+            AND table_name::text = $2::text",
+            &[&table_schema, &table_name],
+        )?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let mut columns: Vec<&String> = schema.fields().iter().map(|f| f.name()).collect();
+    existing.sort();
+    columns.sort();
+    if !existing.is_empty() && existing.iter().ne(columns.iter().copied()) {
+        return Err(anyhow!(
+            "Cannot append to the table {table}: its columns {existing:?} do not match {columns:?}."
+        ));
+    }
+    Ok(())
+}
+
+fn postgres_type(data_type: &DataType) -> String {
+    let pg_type = match data_type {
+        DataType::Boolean => "BOOLEAN",
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => "SMALLINT",
+        DataType::Int32 | DataType::UInt16 => "INTEGER",
+        DataType::Int64 | DataType::UInt32 => "BIGINT",
+        DataType::UInt64 => "NUMERIC(20)",
+        DataType::Float16 | DataType::Float32 => "REAL",
+        DataType::Float64 => "DOUBLE PRECISION",
+        DataType::Decimal128(precision, scale) => return format!("NUMERIC({precision},{scale})"),
+        DataType::Date32 => "DATE",
+        DataType::Date64 | DataType::Timestamp(_, None) => "TIMESTAMP",
+        DataType::Timestamp(_, Some(_)) => "TIMESTAMPTZ",
+        DataType::Time32(_) | DataType::Time64(_) => "TIME",
+        DataType::Binary | DataType::LargeBinary => "BYTEA",
+        _ => "TEXT",
+    };
+    pg_type.to_string()
+}
+
+/// Returns the value as a field of COPY's csv format, in which only nulls
+/// are unquoted.
+fn get_copy_value(array: &ArrayRef, row: usize) -> Result<String> {
+    if array.is_null(row) {
+        return Ok(String::new());
+    }
+    let value = match array.data_type() {
+        DataType::Binary => get_bytea_value(as_generic_binary_array::<i32>(array).value(row)),
+        DataType::LargeBinary => get_bytea_value(as_generic_binary_array::<i64>(array).value(row)),
+        _ => array_value_to_string(array, row)?,
+    };
+    Ok(format!("\"{}\"", value.replace('"', "\"\"")))
+}
+
+fn get_bytea_value(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("\\x{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use arrow::datatypes::TimeUnit;
+
+    use super::*;
+
+    // These tests need a database to create (and drop) the schema "pq test"
+    // in, e.g.
+    // PQ_TEST_POSTGRES_URI=postgresql://postgres@localhost/postgres cargo test -- --ignored
+    fn get_test_database() -> DatabaseSpec {
+        let uri = env::var("PQ_TEST_POSTGRES_URI").expect("PQ_TEST_POSTGRES_URI is not set");
+        DatabaseSpec::parse(&uri).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn query_and_write_postgres_tables() {
+        let database = get_test_database();
+        let mut client = connect(&database).unwrap();
+        client
+            .batch_execute(
+                r#"DROP SCHEMA IF EXISTS "pq test" CASCADE;
+                CREATE SCHEMA "pq test";
+                CREATE TABLE "pq test".types AS SELECT
+                    true AS b, 1::int2 AS i2, 2::int4 AS i4, 3::int8 AS i8,
+                    1.5::float4 AS f4, 2.5::float8 AS f8, 1.25::numeric AS n,
+                    '2022-01-02'::date AS d, 'infinity'::date AS inf,
+                    '2022-01-02 03:04:05.6'::timestamp AS ts, 'x' AS s;"#,
+            )
+            .unwrap();
+
+        let rb = get_record_batch(&mut client, r#"SELECT * FROM "pq test".types"#).unwrap();
+        let types: Vec<DataType> = rb
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.data_type().clone())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                DataType::Boolean,
+                DataType::Int32,
+                DataType::Int32,
+                DataType::Int64,
+                DataType::Float64,
+                DataType::Float64,
+                DataType::Utf8,
+                DataType::Date32,
+                // dates that can't be parsed are kept as text
+                DataType::Utf8,
+                DataType::Timestamp(TimeUnit::Microsecond, None),
+                DataType::Utf8,
+            ]
+        );
+
+        // the results are appended to a table of the current schema
+        let current_schema = DatabaseSpec {
+            current_schema: Some("pq test".to_string()),
+            ..database.clone()
+        };
+        let rbs = [rb];
+        write_record_batches_to_postgres(&rbs, &current_schema, "copied", false).unwrap();
+        write_record_batches_to_postgres(&rbs, &current_schema, "copied", true).unwrap();
+
+        // unqualified tables are looked up in the current schema, and writes
+        // to read-only databases are refused
+        let read_only = DatabaseSpec {
+            read_only: true,
+            ..current_schema
+        };
+        let mut client = connect(&read_only).unwrap();
+        let rb = get_record_batch(&mut client, "SELECT * FROM copied").unwrap();
+        assert_eq!(rb.num_rows(), 2);
+        assert!(client.batch_execute("DROP TABLE copied").is_err());
+
+        let mut client = connect(&database).unwrap();
+        client
+            .batch_execute(r#"DROP SCHEMA "pq test" CASCADE"#)
+            .unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! A backend that runs queries directly on SQLite databases with rusqlite,
+//! rather than through the sqlite_scanner extension of DuckDB. File sources
+//! are read with the shared Arrow readers and loaded into temporary tables,
+//! and the results are written with the shared Arrow writers. Further SQLite
+//! databases are attached with `ATTACH DATABASE`. It also writes results to
+//! SQLite tables for the other backends.
+
+use std::fs;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use log::debug;
+
+use arrow::array::{
+    as_boolean_array, as_generic_binary_array, as_primitive_array, Array, ArrayRef, BinaryArray,
+    Float64Array, Int64Array, StringArray,
+};
+use arrow::datatypes::*;
+use arrow::error::Result as ArrowResult;
+use arrow::ipc;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use prql_compiler::sql::Dialect;
+use prql_compiler::{compile, Options, Target};
+use rusqlite::types::Value as SqliteValue;
+use rusqlite::OpenFlags;
+
+use crate::arrow_io::{
+    get_csv_reader, get_json_reader, get_sheet_reader, get_stdin_reader, write_results_with_arrow,
+    SchemaWithBatches,
+};
+use crate::database::{self, AttachedDatabases, DatabaseScheme, DatabaseSpec};
+use crate::{compression, encoding, xlsx};
+use crate::{get_stdin_format, OutputFormat, OutputOptions, OutputWriter, SourcesType, TempFiles};
+
+#[allow(clippy::too_many_arguments)]
+pub fn query(
+    query: &str,
+    sources: &SourcesType,
+    to: &str,
+    database: Option<&DatabaseSpec>,
+    attached: &AttachedDatabases,
+    format: &OutputFormat,
+    writer: &OutputWriter,
+    output_options: &OutputOptions,
+) -> Result<()> {
+    if let OutputWriter::backend = writer {
+        return Err(anyhow!(
+            "The sqlite backend has no writer of its own. Use --writer arrow."
+        ));
+    }
+    let sql = if query.starts_with("prql ") {
+        let options = Options {
+            target: Target::Sql(Some(Dialect::SQLite)),
+            ..Options::default()
+        };
+        compile(query, &options).map_err(|e| anyhow!(e))?
+    } else {
+        query.to_string()
+    };
+    debug!("sql = {sql:?}");
+
+    let conn = match database {
+        None => {
+            debug!("Opening in-memory SQLite database");
+            rusqlite::Connection::open_in_memory()?
+        }
+        Some(database) if database.scheme == DatabaseScheme::Sqlite => {
+            debug!("Opening SQLite database: dbpath={:?}", database.path);
+            let mut flags = OpenFlags::default();
+            if database.read_only {
+                flags.remove(OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE);
+                flags.insert(OpenFlags::SQLITE_OPEN_READ_ONLY);
+            }
+            rusqlite::Connection::open_with_flags(&database.path, flags)?
+        }
+        Some(database) => {
+            return Err(anyhow!(
+                "The sqlite backend can only query sqlite databases, not {} ones.",
+                database.scheme
+            ))
+        }
+    };
+
+    for (alias, database) in attached.iter() {
+        if database.scheme != DatabaseScheme::Sqlite {
+            return Err(anyhow!(
+                "The sqlite backend can only attach sqlite databases, not {} ones.",
+                database.scheme
+            ));
+        }
+        if !Utf8Path::new(&database.path).exists() {
+            return Err(anyhow!(
+                "The database {:?} attached as {alias:?} does not exist.",
+                database.path
+            ));
+        }
+        let path = if database.read_only {
+            format!("file:{}?mode=ro", database.path)
+        } else {
+            database.path.clone()
+        };
+        debug!("Attaching SQLite database {path:?} as {alias:?}");
+        conn.execute_batch(&format!(
+            "ATTACH DATABASE '{}' AS \"{alias}\";",
+            path.replace('\'', "''")
+        ))?;
+    }
+
+    // decompressed and transcoded copies of sources
+    let mut temp_paths = TempFiles::default();
+
+    for spec in sources.iter() {
+        let (alias, mut source) = (&spec.alias, &spec.source);
+        if let Some(temp_path) = compression::decompress_to_temp_file(source)? {
+            temp_paths.push(temp_path);
+            source = temp_paths.last().unwrap();
+        }
+        if let Some(temp_path) = encoding::transcode_to_temp_file(source, &spec.encoding)? {
+            temp_paths.push(temp_path);
+            source = temp_paths.last().unwrap();
+        }
+        let reader: Option<SchemaWithBatches> =
+            if let Some(stdin_format) = get_stdin_format(source)? {
+                Some(get_stdin_reader(stdin_format, spec)?)
+            } else if let Some((path, sheet)) = xlsx::parse_source(source) {
+                Some(get_sheet_reader(path, sheet)?)
+            } else if source.ends_with(".arrow") || source.ends_with(".feather") {
+                let reader = ipc::reader::FileReader::try_new(fs::File::open(source)?, None)?;
+                Some((reader.schema(), Box::new(reader)))
+            } else if source.ends_with(".csv") || source.ends_with(".tsv") {
+                Some(get_csv_reader(fs::File::open(source)?, source, spec)?)
+            } else if source.ends_with(".json") {
+                Some(get_json_reader(fs::File::open(source)?, spec)?)
+            } else if source.ends_with(".parquet") {
+                let builder = ParquetRecordBatchReaderBuilder::try_new(fs::File::open(source)?)?;
+                Some((builder.schema().clone(), Box::new(builder.build()?)))
+            } else {
+                // a table of the database
+                None
+            };
+
+        match reader {
+            Some((schema, reader)) => {
+                let mut rbs = reader.collect::<ArrowResult<Vec<RecordBatch>>>()?;
+                let mut schema = schema.as_ref().clone();
+                if let Some(columns) = &spec.columns {
+                    let indices = columns
+                        .iter()
+                        .map(|c| schema.index_of(c))
+                        .collect::<ArrowResult<Vec<usize>>>()?;
+                    schema = schema.project(&indices)?;
+                    rbs = rbs
+                        .iter()
+                        .map(|rb| rb.project(&indices))
+                        .collect::<ArrowResult<Vec<RecordBatch>>>()?;
+                }
+                debug!("Loading {source:?} into the temporary table {alias:?}");
+                insert_record_batches_into_sqlite(&conn, "TEMP TABLE", alias, &schema, &rbs)?;
+            }
+            None if alias != source || spec.columns.is_some() => {
+                let columns = spec.columns.as_ref().map_or(String::from("*"), |columns| {
+                    columns
+                        .iter()
+                        .map(|c| format!("\"{}\"", c.replace('"', "\"\"")))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                });
+                conn.execute_batch(&format!(
+                    "CREATE TEMP VIEW \"{}\" AS SELECT {columns} FROM {source};",
+                    alias.replace('"', "\"\"")
+                ))?;
+            }
+            None => {}
+        }
+    }
+
+    let rb = {
+        let mut stmt = conn.prepare(&sql)?;
+        get_record_batch(&mut stmt)?
+    };
+    drop(temp_paths);
+    write_results_with_arrow(&[rb], to, format, output_options)
+}
+
+/// Runs the statement and converts its rows into a record batch. Since
+/// SQLite values are dynamically typed, the type of each column is that of
+/// its values: integer, real (for a mix of integers and reals), blob or
+/// otherwise text.
+fn get_record_batch(stmt: &mut rusqlite::Statement) -> Result<RecordBatch> {
+    let names: Vec<String> = stmt.column_names().iter().map(|n| n.to_string()).collect();
+    let mut columns: Vec<Vec<SqliteValue>> = vec![Vec::new(); names.len()];
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        for (i, column) in columns.iter_mut().enumerate() {
+            column.push(row.get::<_, SqliteValue>(i)?);
+        }
+    }
+
+    let arrays: Vec<ArrayRef> = columns.iter().map(|values| get_array(values)).collect();
+    let fields: Vec<Field> = names
+        .iter()
+        .zip(arrays.iter())
+        .map(|(name, array)| Field::new(name, array.data_type().clone(), true))
+        .collect();
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+}
+
+fn get_array(values: &[SqliteValue]) -> ArrayRef {
+    let all = |f: fn(&SqliteValue) -> bool| {
+        values
+            .iter()
+            .all(|value| *value == SqliteValue::Null || f(value))
+    };
+    let has_values = values.iter().any(|value| *value != SqliteValue::Null);
+    if has_values && all(|value| matches!(value, SqliteValue::Integer(_))) {
+        Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    SqliteValue::Integer(i) => Some(*i),
+                    _ => None,
+                })
+                .collect::<Int64Array>(),
+        )
+    } else if has_values
+        && all(|value| matches!(value, SqliteValue::Integer(_) | SqliteValue::Real(_)))
+    {
+        Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    SqliteValue::Integer(i) => Some(*i as f64),
+                    SqliteValue::Real(f) => Some(*f),
+                    _ => None,
+                })
+                .collect::<Float64Array>(),
+        )
+    } else if has_values && all(|value| matches!(value, SqliteValue::Blob(_))) {
+        Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    SqliteValue::Blob(b) => Some(b.as_slice()),
+                    _ => None,
+                })
+                .collect::<BinaryArray>(),
+        )
+    } else {
+        Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    SqliteValue::Null => None,
+                    SqliteValue::Integer(i) => Some(i.to_string()),
+                    SqliteValue::Real(f) => Some(f.to_string()),
+                    SqliteValue::Text(s) => Some(s.clone()),
+                    SqliteValue::Blob(b) => Some(String::from_utf8_lossy(b).to_string()),
+                })
+                .collect::<StringArray>(),
+        )
+    }
+}
+
+/// Writes the record batches to a table of a SQLite database, which is
+/// replaced unless they are appended to it.
+pub fn write_record_batches_to_sqlite(
+    rbs: &[RecordBatch],
+    dbpath: &str,
+    table: &str,
+    append: bool,
+) -> Result<()> {
+    debug!("Opening SQLite database: dbpath={:?}", dbpath);
+    let mut conn = rusqlite::Connection::open(dbpath)?;
+    let tx = conn.transaction()?;
+    let schema = rbs[0].schema();
+    if append {
+        check_sqlite_append_columns(&tx, table, &schema)?;
+    } else {
+        tx.execute_batch(&format!(
+            "DROP TABLE IF EXISTS {};",
+            database::quote_table_name(table)
+        ))?;
+    }
+    insert_record_batches_into_sqlite(&tx, "TABLE", table, &schema, rbs)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Checks the columns of an existing table against those of the results
+/// about to be appended to it.
+fn check_sqlite_append_columns(
+    conn: &rusqlite::Connection,
+    table: &str,
+    schema: &Schema,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!(
+        "PRAGMA table_info({})",
+        database::quote_table_name(table)
+    ))?;
+    let existing = stmt
+        .query_map([], |row| row.get(1))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let columns: Vec<&String> = schema.fields().iter().map(|f| f.name()).collect();
+    if !existing.is_empty() && existing.iter().ne(columns.iter().copied()) {
+        return Err(anyhow!(
+            "Cannot append to the table {table}: its columns {existing:?} do not match {columns:?}."
+        ));
+    }
+    Ok(())
+}
+
+/// Creates a table (or a `TEMP TABLE`) with the schema, unless it already
+/// exists, and inserts the record batches into it.
+fn insert_record_batches_into_sqlite(
+    conn: &rusqlite::Connection,
+    table_type: &str,
+    table: &str,
+    schema: &Schema,
+    rbs: &[RecordBatch],
+) -> Result<()> {
+    let table = database::quote_table_name(table);
+    let columns: Vec<String> = schema
+        .fields()
+        .iter()
+        .map(|f| {
+            format!(
+                "\"{}\" {}",
+                f.name().replace('"', "\"\""),
+                sqlite_type(f.data_type())
+            )
+        })
+        .collect();
+    let placeholders = vec!["?"; columns.len()].join(", ");
+
+    conn.execute_batch(&format!(
+        "CREATE {table_type} IF NOT EXISTS {table} ({});",
+        columns.join(", ")
+    ))?;
+    let mut stmt = conn.prepare(&format!("INSERT INTO {table} VALUES ({placeholders})"))?;
+    for rb in rbs {
+        for row in 0..rb.num_rows() {
+            let values = rb
+                .columns()
+                .iter()
+                .map(|array| sqlite_value(array, row))
+                .collect::<Result<Vec<SqliteValue>>>()?;
+            stmt.execute(rusqlite::params_from_iter(values))?;
+        }
+    }
+    Ok(())
+}
+
+fn sqlite_type(data_type: &DataType) -> &'static str {
+    match data_type {
+        DataType::Boolean
+        | DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64 => "INTEGER",
+        DataType::Float16 | DataType::Float32 | DataType::Float64 => "REAL",
+        DataType::Decimal128(_, _) | DataType::Decimal256(_, _) => "NUMERIC",
+        DataType::Binary | DataType::LargeBinary => "BLOB",
+        _ => "TEXT",
+    }
+}
+
+fn sqlite_value(array: &ArrayRef, row: usize) -> Result<SqliteValue> {
+    if array.is_null(row) {
+        return Ok(SqliteValue::Null);
+    }
+    let value = match array.data_type() {
+        DataType::Boolean => SqliteValue::Integer(as_boolean_array(array).value(row) as i64),
+        DataType::Int8 => {
+            SqliteValue::Integer(as_primitive_array::<Int8Type>(array).value(row) as i64)
+        }
+        DataType::Int16 => {
+            SqliteValue::Integer(as_primitive_array::<Int16Type>(array).value(row) as i64)
+        }
+        DataType::Int32 => {
+            SqliteValue::Integer(as_primitive_array::<Int32Type>(array).value(row) as i64)
+        }
+        DataType::Int64 => SqliteValue::Integer(as_primitive_array::<Int64Type>(array).value(row)),
+        DataType::UInt8 => {
+            SqliteValue::Integer(as_primitive_array::<UInt8Type>(array).value(row) as i64)
+        }
+        DataType::UInt16 => {
+            SqliteValue::Integer(as_primitive_array::<UInt16Type>(array).value(row) as i64)
+        }
+        DataType::UInt32 => {
+            SqliteValue::Integer(as_primitive_array::<UInt32Type>(array).value(row) as i64)
+        }
+        DataType::UInt64 => SqliteValue::Integer(i64::try_from(
+            as_primitive_array::<UInt64Type>(array).value(row),
+        )?),
+        DataType::Float32 => {
+            SqliteValue::Real(as_primitive_array::<Float32Type>(array).value(row) as f64)
+        }
+        DataType::Float64 => SqliteValue::Real(as_primitive_array::<Float64Type>(array).value(row)),
+        DataType::Binary => {
+            SqliteValue::Blob(as_generic_binary_array::<i32>(array).value(row).to_vec())
+        }
+        DataType::LargeBinary => {
+            SqliteValue::Blob(as_generic_binary_array::<i64>(array).value(row).to_vec())
+        }
+        _ => SqliteValue::Text(array_value_to_string(array, row)?),
+    };
+    Ok(value)
+}
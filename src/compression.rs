@@ -0,0 +1,134 @@
+//! Transparent (de)compression of sources and outputs based on their
+//! extension, e.g. `invoices.csv.gz` or `out.json.zst`.
+
+use std::fs;
+use std::io::prelude::*;
+
+use anyhow::Result;
+use camino::Utf8Path;
+use log::debug;
+
+use crate::get_temp_path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    fn from_extension(ext: &str) -> Option<Compression> {
+        let compression = match ext {
+            "gz" | "gzip" => Compression::Gzip,
+            "zst" | "zstd" => Compression::Zstd,
+            "bz2" => Compression::Bzip2,
+            "xz" => Compression::Xz,
+            _ => return None,
+        };
+        Some(compression)
+    }
+}
+
+/// Splits a path like `invoices.csv.gz` into the path without the compression
+/// extension (`invoices.csv`) and the compression, if any.
+pub fn split_path(path: &str) -> (&str, Option<Compression>) {
+    match path
+        .rsplit_once('.')
+        .and_then(|(stem, ext)| Compression::from_extension(ext).map(|c| (stem, c)))
+    {
+        Some((stem, compression)) => (stem, Some(compression)),
+        None => (path, None),
+    }
+}
+
+pub fn get_decoder(compression: Compression, reader: impl Read + 'static) -> Result<Box<dyn Read>> {
+    let decoder: Box<dyn Read> = match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+    };
+    Ok(decoder)
+}
+
+/// A destination which compresses what is written to it, if its extension
+/// says so. `finish` has to be called once everything has been written, so
+/// that the compressed stream is completed and any errors are reported.
+pub enum Encoder {
+    Plain(Box<dyn Write>),
+    Gzip(flate2::write::GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+    Bzip2(bzip2::write::BzEncoder<Box<dyn Write>>),
+    Xz(xz2::write::XzEncoder<Box<dyn Write>>),
+}
+
+impl Encoder {
+    pub fn finish(self) -> Result<()> {
+        let mut writer = match self {
+            Encoder::Plain(writer) => writer,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Bzip2(encoder) => encoder.finish()?,
+            Encoder::Xz(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn get_mut(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Plain(writer) => writer,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Bzip2(encoder) => encoder,
+            Encoder::Xz(encoder) => encoder,
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+/// Wraps `writer` in an encoder for the compression, if any.
+pub fn get_encoder(compression: Option<Compression>, writer: Box<dyn Write>) -> Result<Encoder> {
+    let encoder = match compression {
+        None => Encoder::Plain(writer),
+        Some(Compression::Gzip) => Encoder::Gzip(flate2::write::GzEncoder::new(
+            writer,
+            flate2::Compression::default(),
+        )),
+        Some(Compression::Zstd) => Encoder::Zstd(zstd::Encoder::new(writer, 0)?),
+        Some(Compression::Bzip2) => Encoder::Bzip2(bzip2::write::BzEncoder::new(
+            writer,
+            bzip2::Compression::default(),
+        )),
+        Some(Compression::Xz) => Encoder::Xz(xz2::write::XzEncoder::new(writer, 6)),
+    };
+    Ok(encoder)
+}
+
+/// Decompresses a compressed source to a temporary file, which keeps the
+/// extension of the uncompressed format so that the backends can read it as
+/// usual, and returns its path. The caller is responsible for removing it.
+pub fn decompress_to_temp_file(path: &str) -> Result<Option<String>> {
+    let (stem, compression) = match split_path(path) {
+        (stem, Some(compression)) => (stem, compression),
+        (_, None) => return Ok(None),
+    };
+    let filename = Utf8Path::new(stem).file_name().unwrap_or(stem);
+    let temp_path = get_temp_path(filename);
+    debug!("Decompressing {path:?} to {temp_path:?}");
+
+    let mut decoder = get_decoder(compression, fs::File::open(path)?)?;
+    std::io::copy(&mut decoder, &mut fs::File::create(&temp_path)?)?;
+    Ok(Some(temp_path))
+}
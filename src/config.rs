@@ -0,0 +1,394 @@
+//! Configuration files (`pq.toml`) with named connections, source aliases,
+//! default settings and profiles, e.g.
+//!
+//! ```toml
+//! [defaults]
+//! format = "csv"
+//!
+//! [connections]
+//! warehouse = "postgresql://analyst@warehouse/analytics"
+//!
+//! [sources]
+//! invoices = "data/invoices.csv?delimiter=;"
+//!
+//! [profiles.prod]
+//! database = "@warehouse"
+//! ```
+//!
+//! The settings are the long names of the command line arguments. They are
+//! added to the arguments unless these are given on the command line or in
+//! the environment, which both take precedence over the configuration.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::{env, fs};
+
+use anyhow::{anyhow, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use log::debug;
+use serde::Deserialize;
+use toml::Value;
+
+const CONFIG_FILENAME: &str = "pq.toml";
+
+// Settings by the long names of the arguments
+type Settings = BTreeMap<String, Value>;
+
+/// The settings of the configuration that apply to the arguments
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Applied {
+    /// The settings as command line arguments, e.g. `--delimiter=;`
+    pub args: Vec<String>,
+    /// The format of the output on stdout, since files and database tables
+    /// are written in the format of their extension or URI
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: Settings,
+    /// Database URIs referenced as `@<name>`
+    #[serde(default)]
+    pub connections: BTreeMap<String, String>,
+    /// Sources (optionally with per-source options) referenced as `@<name>`
+    #[serde(default)]
+    pub sources: BTreeMap<String, String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Settings>,
+    /// The files the configuration was read from
+    #[serde(skip)]
+    pub paths: Vec<String>,
+}
+
+impl Config {
+    /// Reads the user's configuration (`~/.config/pq/pq.toml`) and then
+    /// that of the project (`./pq.toml`), whose entries take precedence.
+    pub fn load() -> Result<Config> {
+        let mut config = Config::default();
+        let user_dir = env::var("XDG_CONFIG_HOME")
+            .ok()
+            .or_else(|| env::var("HOME").ok().map(|home| format!("{home}/.config")));
+        let paths = user_dir
+            .map(|dir| format!("{dir}/pq/{CONFIG_FILENAME}"))
+            .into_iter()
+            .chain([String::from(CONFIG_FILENAME)]);
+        for path in paths {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            debug!("Reading configuration from {path:?}");
+            let file: Config = toml::from_str(&contents)
+                .map_err(|e| anyhow!("Could not read the configuration in {path:?}: {e}"))?;
+            config.defaults.extend(file.defaults);
+            config.connections.extend(file.connections);
+            config.sources.extend(file.sources);
+            config.profiles.extend(file.profiles);
+            config.paths.push(path);
+        }
+        Ok(config)
+    }
+
+    /// Returns the settings of the profile and then of the defaults, except
+    /// for those whose arguments are given on the command line or in the
+    /// environment (as far as `matches` of the `command` tell). The settings
+    /// are top-level arguments, so none apply when a subcommand is given.
+    pub fn apply(
+        &self,
+        profile: Option<&str>,
+        command: &Command,
+        matches: &ArgMatches,
+    ) -> Result<Applied> {
+        let profile_settings = match profile {
+            Some(profile) => Some(self.profiles.get(profile).ok_or_else(|| {
+                anyhow!(
+                    "Unknown profile {profile:?}. The profiles in {:?} are {:?}.",
+                    self.paths,
+                    self.profiles.keys().collect::<Vec<&String>>()
+                )
+            })?),
+            None => None,
+        };
+
+        let mut applied = Applied::default();
+        if matches.subcommand().is_some() {
+            return Ok(applied);
+        }
+        let mut ids: BTreeSet<String> = BTreeSet::new();
+        for (key, value) in profile_settings
+            .into_iter()
+            .chain([&self.defaults])
+            .flatten()
+        {
+            let name = key.replace('_', "-");
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(name.as_str()))
+                .ok_or_else(|| anyhow!("Unknown setting {key:?} in {:?}.", self.paths))?;
+            let id = arg.get_id().to_string();
+            if matches!(
+                matches.value_source(&id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) || !ids.insert(id.clone())
+            {
+                continue;
+            }
+            debug!("Setting {name} from {CONFIG_FILENAME}");
+            match (value, arg.get_action()) {
+                (Value::Array(values), ArgAction::Append) => {
+                    for value in values {
+                        applied
+                            .args
+                            .push(format!("--{name}={}", get_setting_value(value)?));
+                    }
+                }
+                (Value::Array(_), _) => {
+                    return Err(anyhow!("The setting {key:?} only takes one value."))
+                }
+                (Value::Boolean(flag), ArgAction::SetTrue) => {
+                    if *flag {
+                        applied.args.push(format!("--{name}"));
+                    }
+                }
+                (_, ArgAction::SetTrue) => {
+                    return Err(anyhow!("Expected true or false for the setting {key:?}."))
+                }
+                _ if id == "format" => applied.format = Some(get_setting_value(value)?),
+                _ => applied
+                    .args
+                    .push(format!("--{name}={}", get_setting_value(value)?)),
+            }
+        }
+        Ok(applied)
+    }
+
+    /// Replaces a `@<name>` connection, optionally given an alias with
+    /// `<alias>=@<name>`, with its URI. Anything after a `#` (e.g. the table
+    /// of a `--to` destination) is kept.
+    pub fn resolve_connection(&self, arg: &str) -> Result<String> {
+        let (prefix, reference) = split_reference(arg);
+        let name = match reference {
+            Some(name) => name,
+            None => return Ok(arg.to_string()),
+        };
+        let (name, fragment) = match name.split_once('#') {
+            Some((name, table)) => (name, format!("#{table}")),
+            None => (name, String::new()),
+        };
+        let uri = self.connections.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown connection \"@{name}\". The connections in {:?} are {:?}.",
+                self.paths,
+                self.connections.keys().collect::<Vec<&String>>()
+            )
+        })?;
+        Ok(format!("{prefix}{uri}{fragment}"))
+    }
+
+    /// Replaces a `@<name>` source with `<name>=<source>`, or with
+    /// `<alias>=<source>` for `<alias>=@<name>`.
+    pub fn resolve_source(&self, arg: &str) -> Result<String> {
+        let (prefix, reference) = split_reference(arg);
+        let name = match reference {
+            Some(name) => name,
+            None => return Ok(arg.to_string()),
+        };
+        let source = self.sources.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown source \"@{name}\". The sources in {:?} are {:?}.",
+                self.paths,
+                self.sources.keys().collect::<Vec<&String>>()
+            )
+        })?;
+        let alias = if prefix.is_empty() {
+            format!("{name}=")
+        } else {
+            prefix.to_string()
+        };
+        Ok(format!("{alias}{source}"))
+    }
+}
+
+/// Splits `<alias>=@<name>` or `@<name>` into the `<alias>=` prefix, if any,
+/// and the name.
+fn split_reference(arg: &str) -> (&str, Option<&str>) {
+    if let Some(name) = arg.strip_prefix('@') {
+        return ("", Some(name));
+    }
+    match arg.split_once("=@") {
+        Some((alias, name)) if !alias.contains(':') && !alias.contains('/') => {
+            (&arg[..alias.len() + 1], Some(name))
+        }
+        _ => (arg, None),
+    }
+}
+
+fn get_setting_value(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        value => Err(anyhow!(
+            "Expected a string, number or boolean setting, got {value}."
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, CommandFactory, Parser};
+
+    use super::*;
+    use crate::Cli;
+
+    const CONFIG: &str = r#"
+        [defaults]
+        format = "csv"
+        delimiter = ";"
+        no_header = true
+        skip_rows = 2
+
+        [connections]
+        warehouse = "postgresql://analyst@warehouse/analytics"
+
+        [sources]
+        invoices = "data/invoices.csv?delimiter=;"
+
+        [profiles.prod]
+        database = ["@warehouse", "lookup=sqlite://lookup.sqlite"]
+        delimiter = "|"
+        no_header = false
+    "#;
+
+    fn apply(profile: Option<&str>, args: &[&str]) -> Result<Applied> {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let matches = Cli::command()
+            .try_get_matches_from(["pq"].iter().chain(args))
+            .unwrap();
+        config.apply(profile, &Cli::command(), &matches)
+    }
+
+    #[test]
+    fn apply_defaults() {
+        let applied = apply(None, &[]).unwrap();
+        assert_eq!(
+            applied.args,
+            ["--delimiter=;", "--no-header", "--skip-rows=2"]
+        );
+        assert_eq!(applied.format.as_deref(), Some("csv"));
+    }
+
+    #[test]
+    fn apply_profile_before_defaults() {
+        let applied = apply(Some("prod"), &[]).unwrap();
+        assert_eq!(
+            applied.args,
+            [
+                "--database=@warehouse",
+                "--database=lookup=sqlite://lookup.sqlite",
+                "--delimiter=|",
+                "--skip-rows=2",
+            ]
+        );
+        assert!(apply(Some("dev"), &[]).is_err());
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let applied = apply(
+            Some("prod"),
+            &[
+                "--delimiter",
+                ",",
+                "-d",
+                "duckdb://x.duckdb",
+                "--format",
+                "json",
+            ],
+        )
+        .unwrap();
+        assert_eq!(applied.args, ["--skip-rows=2"]);
+        assert_eq!(applied.format, None);
+    }
+
+    #[test]
+    fn environment_takes_precedence() {
+        // PATH is set for any test run, so nothing is added to the
+        // environment
+        let command = Command::new("pq").arg(Arg::new("path").long("path").env("PATH"));
+        let config: Config = toml::from_str("[defaults]\npath = \"/bin\"").unwrap();
+        let matches = command.clone().try_get_matches_from(["pq"]).unwrap();
+        let applied = config.apply(None, &command, &matches).unwrap();
+        assert_eq!(applied, Applied::default());
+    }
+
+    #[test]
+    fn subcommands_take_no_settings() {
+        for args in [
+            &["parquet-meta", "x.parquet"][..],
+            &[
+                "copy",
+                "-d",
+                "sqlite://a.sqlite",
+                "--to=sqlite://b.sqlite#t",
+            ],
+        ] {
+            let applied = apply(Some("prod"), args).unwrap();
+            assert_eq!(applied, Applied::default());
+            // the arguments are added as in main
+            let argv = ["pq"]
+                .into_iter()
+                .chain(applied.args.iter().map(|arg| arg.as_str()))
+                .chain(args.iter().copied());
+            assert!(Cli::try_parse_from(argv).is_ok(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for settings in [
+            "unknown = 1",
+            "delimiter = [\";\", \",\"]",
+            "no_header = \"yes\"",
+            "to = { path = \"out.csv\" }",
+        ] {
+            let config: Config = toml::from_str(&format!("[defaults]\n{settings}")).unwrap();
+            let matches = Cli::command().try_get_matches_from(["pq"]).unwrap();
+            assert!(
+                config.apply(None, &Cli::command(), &matches).is_err(),
+                "{settings}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_references() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            config
+                .resolve_connection("@warehouse#sales.invoices")
+                .unwrap(),
+            "postgresql://analyst@warehouse/analytics#sales.invoices"
+        );
+        assert_eq!(
+            config.resolve_connection("dw=@warehouse").unwrap(),
+            "dw=postgresql://analyst@warehouse/analytics"
+        );
+        assert_eq!(
+            config.resolve_connection("sqlite://a.sqlite").unwrap(),
+            "sqlite://a.sqlite"
+        );
+        assert!(config.resolve_connection("@unknown").is_err());
+        assert_eq!(
+            config.resolve_source("@invoices").unwrap(),
+            "invoices=data/invoices.csv?delimiter=;"
+        );
+        assert_eq!(
+            config.resolve_source("i=@invoices").unwrap(),
+            "i=data/invoices.csv?delimiter=;"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Options for delimited text (csv and tsv) files.
+//!
+//! The arrow csv reader and writer only understand a delimiter and whether
+//! there is a header, so sources using any of the other options are first
+//! normalised to standard csv, and output is rewritten after arrow wrote it.
+
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Result};
+
+use crate::on_error::{Rejected, Row, Rows};
+
+/// Options for reading or writing delimited text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    /// The field delimiter, which defaults to a comma (or a tab for tsv)
+    pub delimiter: Option<u8>,
+    pub quote: u8,
+    pub escape: Option<u8>,
+    pub header: bool,
+    /// The string that represents a null value
+    pub null_value: Option<String>,
+    /// The number of lines to skip before the header (or the first row)
+    pub skip_rows: usize,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: None,
+            quote: b'"',
+            escape: None,
+            header: true,
+            null_value: None,
+            skip_rows: 0,
+        }
+    }
+}
+
+impl CsvOptions {
+    /// Returns the delimiter to use for `path`, defaulting to a tab for tsv files
+    pub fn get_delimiter(&self, path: &str) -> u8 {
+        let (path, _) = crate::compression::split_path(path);
+        self.delimiter
+            .unwrap_or(if path.ends_with("tsv") { b'\t' } else { b',' })
+    }
+
+    /// Returns whether arrow can read or write the csv with these options
+    /// directly, i.e. without it being normalised or rewritten.
+    pub fn is_simple(&self) -> bool {
+        self.quote == b'"'
+            && self.escape.is_none()
+            && self.null_value.is_none()
+            && self.skip_rows == 0
+    }
+}
+
+/// Parses a single (ASCII) character option such as a delimiter. `\t` and
+/// `tab` are accepted for tabs, since they are awkward to pass on the command
+/// line.
+pub fn parse_char(s: &str) -> Result<u8> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(anyhow!("Expected a single ASCII character, got {s:?}.")),
+    }
+}
+
+/// Reads delimited text from `input` and returns it as standard csv (comma
+/// separated and double quoted), without the skipped rows and with null
+/// values emptied.
+pub fn normalise(mut input: impl Read, path: &str, options: &CsvOptions) -> Result<Vec<u8>> {
+    let mut buf: Vec<u8> = Vec::new();
+    input.read_to_end(&mut buf)?;
+    let start = buf
+        .split_inclusive(|b| *b == b'\n')
+        .take(options.skip_rows)
+        .map(|line| line.len())
+        .sum::<usize>();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(options.get_delimiter(path))
+        .quote(options.quote)
+        .escape(options.escape)
+        .from_reader(&buf[start..]);
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for (i, record) in reader.byte_records().enumerate() {
+        let record = record?;
+        let is_header = i == 0 && options.header;
+        let fields = record.iter().map(|field| match &options.null_value {
+            Some(null_value) if !is_header && field == null_value.as_bytes() => &b""[..],
+            _ => field,
+        });
+        writer.write_record(fields)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// Reads delimited text like `normalise`, but returns the normalised header
+/// and rows separately, rejecting the rows with a different number of fields
+/// than the header (or the first row).
+pub fn split_rows(
+    mut input: impl Read,
+    path: &str,
+    options: &CsvOptions,
+) -> Result<(Rows, Vec<Rejected>)> {
+    let mut buf: Vec<u8> = Vec::new();
+    input.read_to_end(&mut buf)?;
+    let start = buf
+        .split_inclusive(|b| *b == b'\n')
+        .take(options.skip_rows)
+        .map(|line| line.len())
+        .sum::<usize>();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(options.get_delimiter(path))
+        .quote(options.quote)
+        .escape(options.escape)
+        .from_reader(&buf[start..]);
+    let mut rows = Rows::default();
+    let mut rejected: Vec<Rejected> = Vec::new();
+    let mut num_fields: Option<usize> = None;
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let (line, begin) = (reader.position().line(), reader.position().byte() as usize);
+        if !reader.read_byte_record(&mut record)? {
+            break;
+        }
+        let end = reader.position().byte() as usize;
+        let raw = String::from_utf8_lossy(&buf[start + begin..start + end])
+            .trim_end()
+            .to_string();
+        let line = line as usize + options.skip_rows;
+        let is_header = num_fields.is_none() && options.header;
+        let expected = *num_fields.get_or_insert(record.len());
+        if record.len() != expected {
+            rejected.push(Rejected {
+                line,
+                record: raw,
+                reason: format!("Expected {expected} fields, found {}", record.len()),
+            });
+            continue;
+        }
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(record.iter().map(|field| match &options.null_value {
+            Some(null_value) if !is_header && field == null_value.as_bytes() => &b""[..],
+            _ => field,
+        }))?;
+        let data = writer.into_inner()?;
+        if is_header {
+            rows.header = data;
+        } else {
+            rows.rows.push(Row {
+                line,
+                record: raw,
+                data,
+            });
+        }
+    }
+    Ok((rows, rejected))
+}
+
+/// Rewrites standard csv, as written by arrow, to `dest` with the delimiter,
+/// quoting and null value of the options. Empty values are written as the
+/// null value, since arrow doesn't distinguish them from nulls.
+pub fn rewrite(
+    input: &[u8],
+    dest: &mut dyn Write,
+    path: &str,
+    has_headers: bool,
+    options: &CsvOptions,
+) -> Result<()> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(input);
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(options.get_delimiter(path))
+        .quote(options.quote)
+        .double_quote(options.escape.is_none())
+        .escape(options.escape.unwrap_or(b'\\'))
+        .from_writer(dest);
+    for (i, record) in reader.byte_records().enumerate() {
+        let record = record?;
+        let is_header = i == 0 && has_headers;
+        let fields = record.iter().map(|field| match &options.null_value {
+            Some(null_value) if !is_header && field.is_empty() => null_value.as_bytes(),
+            _ => field,
+        });
+        writer.write_record(fields)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Parses the header line of a csv file with the given options
+pub fn parse_header(line: &str, path: &str, options: &CsvOptions) -> Result<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(options.get_delimiter(path))
+        .quote(options.quote)
+        .escape(options.escape)
+        .from_reader(line.as_bytes());
+    match reader.records().next() {
+        Some(record) => Ok(record?.iter().map(|c| c.to_string()).collect()),
+        None => Ok(Vec::new()),
+    }
+}
@@ -0,0 +1,88 @@
+//! Transcoding of csv and json sources in other character encodings (e.g.
+//! Latin-1 or Windows-1252 exports) to UTF-8, which is all the backends can
+//! read. Byte order marks are stripped regardless of the encoding.
+
+use std::fs;
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use log::debug;
+
+use crate::get_temp_path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    /// UTF-8 if the source is valid UTF-8, otherwise Windows-1252
+    Auto,
+    Other(&'static Encoding),
+}
+
+/// Parses `auto` or an encoding label such as `latin1` or `windows-1252`
+pub fn parse_encoding(label: &str) -> Result<TextEncoding> {
+    if label == "auto" {
+        return Ok(TextEncoding::Auto);
+    }
+    match Encoding::for_label(label.as_bytes()) {
+        Some(encoding) if encoding == UTF_8 => Ok(TextEncoding::Utf8),
+        Some(encoding) => Ok(TextEncoding::Other(encoding)),
+        None => Err(anyhow!("Unknown encoding {label:?}.")),
+    }
+}
+
+/// Returns the encoding to decode `buf` with, or `None` if it is UTF-8
+/// without a byte order mark and can be read as it is.
+fn get_source_encoding(buf: &[u8], encoding: &TextEncoding) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(buf) {
+        return Some(encoding);
+    }
+    match encoding {
+        TextEncoding::Utf8 => None,
+        TextEncoding::Auto if std::str::from_utf8(buf).is_ok() => None,
+        TextEncoding::Auto => Some(WINDOWS_1252),
+        TextEncoding::Other(encoding) => Some(encoding),
+    }
+}
+
+/// Returns `buf` as UTF-8 without a byte order mark
+pub fn transcode(buf: Vec<u8>, encoding: &TextEncoding) -> Vec<u8> {
+    match get_source_encoding(&buf, encoding) {
+        Some(source_encoding) => {
+            debug!("Transcoding from {}", source_encoding.name());
+            let (text, _) = source_encoding.decode_with_bom_removal(&buf);
+            text.into_owned().into_bytes()
+        }
+        None => buf,
+    }
+}
+
+/// Transcodes a csv or json source that isn't UTF-8, or has a byte order
+/// mark, to a temporary file and returns its path. The caller is responsible
+/// for removing it.
+pub fn transcode_to_temp_file(path: &str, encoding: &TextEncoding) -> Result<Option<String>> {
+    if ![".csv", ".tsv", ".json"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+    {
+        return Ok(None);
+    }
+    if *encoding == TextEncoding::Utf8 {
+        // only the byte order mark needs to be checked
+        let mut start: Vec<u8> = Vec::new();
+        fs::File::open(path)?.take(3).read_to_end(&mut start)?;
+        if Encoding::for_bom(&start).is_none() {
+            return Ok(None);
+        }
+    }
+    let buf = fs::read(path)?;
+    if get_source_encoding(&buf, encoding).is_none() {
+        return Ok(None);
+    }
+    let filename = Utf8Path::new(path).file_name().unwrap_or(path);
+    let temp_path = get_temp_path(&format!("utf8-{filename}"));
+    debug!("Transcoding {path:?} to {temp_path:?}");
+    fs::write(&temp_path, transcode(buf, encoding))?;
+    Ok(Some(temp_path))
+}
@@ -1,52 +1,204 @@
 #![allow(unused)]
 
+mod arrow_io;
 mod backends;
+mod compression;
+mod config;
+mod database;
+mod delimited;
+mod encoding;
+mod on_error;
+mod parquet_meta;
+mod renderers;
+mod schema;
+mod xlsx;
 
 use anyhow::{anyhow, Result};
 use log::{debug, error, info, warn};
 
 use camino::Utf8Path;
 use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fmt::{self, Debug, Display};
 use std::io::prelude::*;
-use std::{fs, io};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, io};
 
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use prql_compiler::{compile, Options, PRQL_VERSION};
 
+use database::{AttachedDatabases, DatabaseScheme, DatabaseSpec};
+use delimited::CsvOptions;
+use encoding::TextEncoding;
+use on_error::OnError;
+use schema::SchemaSpec;
+
 cfg_if::cfg_if! {
     if #[cfg(feature = "datafusion")] {
         const DEFAULT_BACKEND: Backend = Backend::datafusion;
     } else if #[cfg(feature = "duckdb")] {
         const DEFAULT_BACKEND: Backend = Backend::duckdb;
+    } else if #[cfg(feature = "sqlite")] {
+        const DEFAULT_BACKEND: Backend = Backend::sqlite;
+    } else if #[cfg(feature = "postgres")] {
+        const DEFAULT_BACKEND: Backend = Backend::postgres;
     }
 }
 
-const SUPPORTED_FILE_TYPES: [&str; 4] = ["csv", "json", "parquet", "avro"];
+const SUPPORTED_FILE_TYPES: [&str; 8] = [
+    "csv", "tsv", "json", "parquet", "avro", "arrow", "feather", "xlsx",
+];
+const SUPPORTED_STDIN_FORMATS: [&str; 6] = ["arrows", "arrow", "csv", "tsv", "json", "ndjson"];
+const SUPPORTED_DATABASE_DESTINATIONS: &[&str] = &[
+    #[cfg(feature = "duckdb")]
+    "duckdb://",
+    #[cfg(feature = "sqlite")]
+    "sqlite://",
+    #[cfg(feature = "postgres")]
+    "postgresql://",
+];
+const SUPPORTED_SOURCE_OPTIONS: [&str; 10] = [
+    "delimiter",
+    "quote",
+    "escape",
+    "header",
+    "null_value",
+    "skip_rows",
+    "columns",
+    "schema",
+    "infer_rows",
+    "encoding",
+];
 
 // Some type aliases for consistency
 type FromType = Vec<String>;
-type SourcesType = Vec<(String, String)>;
+type SourcesType = Vec<SourceSpec>;
 
 /// pq: query and transform data with PRQL
 #[derive(Parser, Debug)]
 #[clap(
     name = env!("CARGO_PKG_NAME"),
     version = env!("CARGO_PKG_VERSION"),
-    about = format!("{} version {} (https://prql-lang.org)", env!("CARGO_PKG_DESCRIPTION"), PRQL_VERSION.to_string())
+    about = format!("{} version {} (https://prql-lang.org)", env!("CARGO_PKG_DESCRIPTION"), *PRQL_VERSION),
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
-    /// The file(s) to read data FROM if given
-    #[clap(short, long, value_parser, env = "PQ_FROM")]
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// The file(s) to read data FROM if given. Use - (or -:<format>) to read from stdin
+    #[clap(short, long, value_parser, allow_hyphen_values = true, env = "PQ_FROM")]
     from: Vec<String>,
 
-    /// The file to write TO if given, otherwise stdout
+    /// The file to write TO if given, otherwise stdout. Use <uri>#<table> to write to a database table
     #[clap(short, long, value_parser, default_value = "-", env = "PQ_TO")]
     to: String,
 
-    /// The database to connect to
+    /// Append to the output TO rather than overwriting it
+    #[clap(long, value_parser, env = "PQ_APPEND")]
+    append: bool,
+
+    /// The style to use for json output
+    #[clap(
+        long,
+        value_enum,
+        value_parser,
+        default_value = "lines",
+        env = "PQ_JSON_STYLE"
+    )]
+    json_style: JsonStyle,
+
+    /// The field delimiter of csv sources [default: , or a tab for tsv]
+    #[clap(long, value_parser = delimited::parse_char, env = "PQ_DELIMITER")]
+    delimiter: Option<u8>,
+
+    /// The quote character of csv sources
+    #[clap(long, value_parser = delimited::parse_char, default_value = "\"", env = "PQ_QUOTE")]
+    quote: u8,
+
+    /// The escape character of csv sources, otherwise quotes are escaped by doubling them
+    #[clap(long, value_parser = delimited::parse_char, env = "PQ_ESCAPE")]
+    escape: Option<u8>,
+
+    /// Read the first row of csv sources as data rather than as the header
+    #[clap(long, value_parser, env = "PQ_NO_HEADER")]
+    no_header: bool,
+
+    /// The string that represents null values in csv sources
+    #[clap(long, value_parser, env = "PQ_NULL_VALUE")]
+    null_value: Option<String>,
+
+    /// The number of lines to skip at the start of csv sources
+    #[clap(long, value_parser, default_value = "0", env = "PQ_SKIP_ROWS")]
+    skip_rows: usize,
+
+    /// The number of rows of csv and json sources to infer the column types from
+    #[clap(long, value_parser, env = "PQ_INFER_ROWS")]
+    infer_rows: Option<usize>,
+
+    /// What to do with malformed rows of csv and json sources: fail, skip or quarantine=<path>
+    #[clap(long, value_parser = on_error::parse_on_error, default_value = "fail", env = "PQ_ON_ERROR")]
+    on_error: OnError,
+
+    /// The character encoding of csv and json sources, e.g. latin1, or auto to detect it
+    #[clap(long, value_parser = encoding::parse_encoding, default_value = "utf-8", env = "PQ_ENCODING")]
+    encoding: TextEncoding,
+
+    /// The field delimiter of csv output [default: , or a tab for tsv]
+    #[clap(long, value_parser = delimited::parse_char, env = "PQ_OUTPUT_DELIMITER")]
+    output_delimiter: Option<u8>,
+
+    /// The quote character of csv output
+    #[clap(long, value_parser = delimited::parse_char, default_value = "\"", env = "PQ_OUTPUT_QUOTE")]
+    output_quote: u8,
+
+    /// The escape character of csv output, otherwise quotes are escaped by doubling them
+    #[clap(long, value_parser = delimited::parse_char, env = "PQ_OUTPUT_ESCAPE")]
+    output_escape: Option<u8>,
+
+    /// Don't write a header row to csv output
+    #[clap(long, value_parser, env = "PQ_OUTPUT_NO_HEADER")]
+    output_no_header: bool,
+
+    /// The string to write for null values in csv output
+    #[clap(long, value_parser, env = "PQ_OUTPUT_NULL_VALUE")]
+    output_null_value: Option<String>,
+
+    /// The compression codec of parquet output, e.g. zstd
+    #[clap(long, value_parser = parse_parquet_compression, env = "PQ_PARQUET_COMPRESSION")]
+    parquet_compression: Option<ParquetCompression>,
+
+    /// The maximum number of rows in each row group of parquet output
+    #[clap(long, value_parser, env = "PQ_ROW_GROUP_SIZE")]
+    row_group_size: Option<usize>,
+
+    /// The level at which statistics are written to parquet output
+    #[clap(long, value_enum, value_parser, env = "PQ_PARQUET_STATISTICS")]
+    parquet_statistics: Option<ParquetStatistics>,
+
+    /// Whether to use dictionary encoding for parquet output
+    #[clap(long, value_parser, env = "PQ_PARQUET_DICTIONARY")]
+    parquet_dictionary: Option<bool>,
+
+    /// The database to connect to, or @<name> for a connection of pq.toml. Repeat as <alias>=<uri> to attach further databases, whose tables are referenced as <alias>.<table>
     #[clap(short, long, value_parser, env = "PQ_DATABASE")]
-    database: Option<String>,
+    database: Vec<String>,
+
+    /// The profile of pq.toml whose settings to use
+    #[clap(long, value_parser, global = true, env = "PQ_PROFILE")]
+    profile: Option<String>,
+
+    /// A directory with DuckDB extensions (<name>.duckdb_extension) to load instead of installing them
+    #[clap(long, value_parser, env = "PQ_DUCKDB_EXTENSION_DIR")]
+    duckdb_extension_dir: Option<String>,
+
+    /// Open the databases read-only, even if --allow-writes is given (e.g. by PQ_ALLOW_WRITES)
+    #[clap(long, value_parser, default_value = "false", env = "PQ_READ_ONLY")]
+    read_only: bool,
+
+    /// Allow queries to write to the databases, which are otherwise opened read-only
+    #[clap(long, value_parser, default_value = "false", env = "PQ_ALLOW_WRITES")]
+    allow_writes: bool,
 
     /// The backend to use to process the query
     #[clap(short, long, value_parser, default_value = "auto", env = "PQ_BACKEND")]
@@ -80,21 +232,68 @@ struct Cli {
     query: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the metadata (row groups, column chunks and statistics) of a parquet file
+    ParquetMeta {
+        /// The parquet file to inspect
+        #[clap(value_parser)]
+        file: String,
+
+        /// The format to print the metadata in
+        #[clap(long, value_enum, value_parser, default_value = "table")]
+        format: parquet_meta::MetadataFormat,
+    },
+    /// Run a query on a database and copy the results into a table of another database
+    Copy {
+        /// The database to run the query on
+        #[clap(short, long, value_parser, env = "PQ_DATABASE")]
+        database: String,
+
+        /// The table to copy the results to, as <uri>#<table>
+        #[clap(short, long, value_parser)]
+        to: String,
+
+        /// Append to the table rather than replacing it
+        #[clap(long, value_parser, default_value = "false")]
+        append: bool,
+
+        /// set this to pass a SQL query rather than a PRQL one
+        #[clap(long, value_parser, default_value = "false")]
+        sql: bool,
+
+        /// The PRQL query to be processed if given, otherwise read from stdin
+        #[clap(value_parser, default_value = "-")]
+        query: String,
+    },
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 #[allow(non_camel_case_types)]
 pub enum Backend {
     auto,
     datafusion,
     duckdb,
+    postgres,
+    sqlite,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 #[allow(non_camel_case_types)]
 pub enum OutputFormat {
     csv,
+    tsv,
     json,
     parquet,
     table,
+    arrow,
+    arrows,
+    markdown,
+    html,
+    latex,
+    xlsx,
+    #[value(skip)]
+    database,
 }
 
 impl fmt::Display for OutputFormat {
@@ -112,16 +311,185 @@ pub enum OutputWriter {
     backend,
 }
 
-fn main() -> Result<()> {
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum JsonStyle {
+    /// One JSON object per line (NDJSON)
+    lines,
+    /// A single JSON array of objects
+    array,
+    /// A pretty printed JSON array of objects
+    pretty,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum ParquetCompression {
+    uncompressed,
+    snappy,
+    gzip,
+    brotli,
+    lz4,
+    zstd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum ParquetStatistics {
+    /// No statistics
+    none,
+    /// Statistics for each column chunk
+    chunk,
+    /// Statistics for each page as well as for each column chunk
+    page,
+}
+
+/// Options for writing parquet output, which otherwise uses the defaults of
+/// the parquet writer
+#[derive(Debug, Default)]
+pub struct ParquetOptions {
+    pub compression: Option<ParquetCompression>,
+    pub row_group_size: Option<usize>,
+    pub statistics: Option<ParquetStatistics>,
+    pub dictionary: Option<bool>,
+}
+
+/// A source to read FROM along with the options to read it with
+#[derive(Debug, Clone)]
+pub struct SourceSpec {
+    /// The name of the table the source is registered as
+    pub alias: String,
+    /// The file, database table or stdin (`-`) to read from
+    pub source: String,
+    pub csv: CsvOptions,
+    /// The columns to read, otherwise all of them
+    pub columns: Option<Vec<String>>,
+    /// The column types to use instead of the inferred ones
+    pub schema: Option<SchemaSpec>,
+    /// The number of rows to infer the column types from
+    pub infer_rows: Option<usize>,
+    /// What to do with malformed rows
+    pub on_error: OnError,
+    pub encoding: TextEncoding,
+}
+
+/// Options that control how the output is written
+#[derive(Debug)]
+pub struct OutputOptions {
+    pub append: bool,
+    /// The directory to load DuckDB extensions from when writing to a DuckDB database
+    pub duckdb_extension_dir: Option<String>,
+    pub json_style: JsonStyle,
+    pub csv: CsvOptions,
+    pub parquet: ParquetOptions,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        // errors may contain database URIs, whose passwords are masked
+        eprintln!("Error: {}", database::redact(&format!("{e:?}")));
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     env_logger::init();
     dotenvy::dotenv().ok();
 
-    let args = Cli::parse();
-    debug!("args = {args:?}");
+    // the settings of pq.toml are added to the arguments that aren't given on
+    // the command line or in the environment, so the precedence is:
+    // arguments, environment, the profile and then the defaults
+    let config = config::Config::load()?;
+    let matches = Cli::command().get_matches();
+    let profile = matches.get_one::<String>("profile").map(|p| p.as_str());
+    let applied = config.apply(profile, &Cli::command(), &matches)?;
+    let config_format = applied
+        .format
+        .as_deref()
+        .map(|format| OutputFormat::from_str(format, true))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid format in {:?}: {e}", config.paths))?;
+
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    argv.splice(1..1, applied.args.into_iter().map(OsString::from));
+    let mut args = Cli::parse_from(argv);
+    debug!("args = {}", database::redact(&format!("{args:?}")));
+
+    // args.command
+    match args.command.take() {
+        Some(Command::ParquetMeta { file, format }) => {
+            return parquet_meta::print_metadata(&file, &format);
+        }
+        Some(Command::Copy {
+            database,
+            to,
+            append,
+            sql,
+            query,
+        }) => {
+            // a copy is a query of the database with a database table as
+            // its destination
+            let to = config.resolve_connection(&to)?;
+            if !to.contains("://") {
+                return Err(anyhow!(
+                    "pq copy writes to a database table. Use --to <uri>#<table>."
+                ));
+            }
+            args.database = vec![database];
+            args.to = to;
+            args.append = append;
+            args.sql = sql;
+            args.query = query;
+        }
+        None => {}
+    }
+
+    // @<name> references to the connections and sources of pq.toml
+    args.database = args
+        .database
+        .iter()
+        .map(|arg| config.resolve_connection(arg))
+        .collect::<Result<Vec<String>>>()?;
+    args.from = args
+        .from
+        .iter()
+        .map(|arg| config.resolve_source(arg))
+        .collect::<Result<Vec<String>>>()?;
+    args.to = config.resolve_connection(&args.to)?;
+
+    // args.delimiter etc.
+    // the options for reading every source, unless they are given per source
+    let defaults = SourceSpec {
+        alias: String::new(),
+        source: String::new(),
+        csv: CsvOptions {
+            delimiter: args.delimiter,
+            quote: args.quote,
+            escape: args.escape,
+            header: !args.no_header,
+            null_value: args.null_value,
+            skip_rows: args.skip_rows,
+        },
+        columns: None,
+        schema: None,
+        infer_rows: args.infer_rows,
+        on_error: args.on_error,
+        encoding: args.encoding,
+    };
+    debug!("defaults = {defaults:?}");
+
+    // args.from
+    // determine the sources
+    let sources = standardise_sources(&args.from, &defaults)?;
 
     // args.query
     let mut query: String;
     if args.query == "-" {
+        if sources.iter().any(|spec| spec.source.starts_with('-')) {
+            return Err(anyhow!(
+                "Cannot read both the query and a source from stdin. Pass the query as an argument."
+            ));
+        }
         if atty::is(atty::Stream::Stdin) {
             println!("Enter QUERY, then press Ctrl-d:");
             println!();
@@ -137,14 +505,10 @@ fn main() -> Result<()> {
     query = query.trim().to_string();
     debug!("query = {query:?}");
 
-    // args.from
-    // determine the sources
-    let sources = standardise_sources(&args.from)?;
-
     if !args.sql {
         // insert `from` clause in main pipeline if not given
         if !query.contains("from") && !sources.is_empty() {
-            query = format!("from `{}` |{query}", sources.last().unwrap().0);
+            query = format!("from `{}` |{query}", sources.last().unwrap().alias);
         }
         debug!("query = {query:?}");
     }
@@ -153,23 +517,42 @@ fn main() -> Result<()> {
     if !args.sql && !query.starts_with("prql ") {
         // prepend a PRQL header to signal this is a PRQL query rather than a SQL one
         // FIXME: When the backend is DuckDB, the target should be sql.duckdb
-        query = format!("prql version:'{}' target:sql.generic\n{query}", PRQL_VERSION.to_string())
+        query = format!(
+            "prql version:'{}' target:sql.generic\n{query}",
+            *PRQL_VERSION
+        )
     }
     debug!("query = {query:?}");
 
     // args.to
     let to = args.to.to_string().trim_end_matches('/').to_string();
-    debug!("to = {to:?}");
+    debug!("to = {:?}", database::redact(&to));
+    // the output format is given by the extension before any compression one
+    let (to_uncompressed, to_compression) = compression::split_path(&to);
+    debug!("to_compression = {to_compression:?}");
 
     debug!("args.format = {0:?}", &args.format);
     let format: OutputFormat;
     if let Some(args_format) = args.format {
         if to == "-"
             && atty::is(atty::Stream::Stdout)
-            && vec![OutputFormat::parquet].contains(&args_format)
+            && [
+                OutputFormat::parquet,
+                OutputFormat::arrow,
+                OutputFormat::arrows,
+                OutputFormat::xlsx,
+            ]
+            .contains(&args_format)
         {
             return Err(anyhow!("Cannot print format={args_format:?} to stdout."));
-        } else if to != "-" && !to.ends_with(&args_format.to_string()) {
+        } else if to != "-"
+            && !to_uncompressed.ends_with(&args_format.to_string())
+            && to_uncompressed
+                .rsplit('.')
+                .next()
+                .and_then(get_format_from_extension)
+                != Some(args_format)
+        {
             return Err(anyhow!(
                 "to={to:?} is incompatible with format={args_format:?}!"
             ));
@@ -178,57 +561,208 @@ fn main() -> Result<()> {
     } else {
         // i.e. args.format.is_none()
         if to == "-" {
-            format = OutputFormat::table;
+            format = config_format.unwrap_or(OutputFormat::table);
+        } else if to.contains("://") {
+            let uri = to.split('#').next().unwrap_or_default();
+            let scheme = DatabaseSpec::parse(uri)?.scheme;
+            if !SUPPORTED_DATABASE_DESTINATIONS.contains(&format!("{scheme}://").as_str()) {
+                return Err(anyhow!(
+                    "Writing to {to:?} is not supported. Supported database destinations are {SUPPORTED_DATABASE_DESTINATIONS:?}."
+                ));
+            } else if !to.contains('#') {
+                return Err(anyhow!(
+                    "No table name given in to={to:?}. Use the form <uri>#<table>."
+                ));
+            }
+            format = OutputFormat::database;
         } else {
-            format = match to
+            let fileext = to_uncompressed
                 .split(".")
                 .last()
-                .ok_or(anyhow!("No extension format found in {to:?}"))?
-            {
-                "csv" => OutputFormat::csv,
-                "json" => OutputFormat::json,
-                "parquet" => OutputFormat::parquet,
-                "table" | "tbl" => OutputFormat::table,
-                fileext => return Err(anyhow!(".{fileext} files are currently not supported.")),
-            };
+                .ok_or(anyhow!("No extension format found in {to:?}"))?;
+            format = get_format_from_extension(fileext)
+                .ok_or(anyhow!(".{fileext} files are currently not supported."))?;
         }
         info!("inferred format = {format:?}");
     }
     debug!("format = {0:?}", &args.format);
 
+    // args.append
+    debug!("args.append = {0:?}", &args.append);
+    if args.append {
+        if to == "-" {
+            return Err(anyhow!("Cannot append to stdout."));
+        } else if to_compression.is_some() {
+            return Err(anyhow!("Cannot append to the compressed output {to:?}."));
+        } else if [
+            OutputFormat::arrow,
+            OutputFormat::arrows,
+            OutputFormat::xlsx,
+        ]
+        .contains(&format)
+        {
+            return Err(anyhow!("Cannot append to format={format:?}."));
+        } else if format == OutputFormat::json && args.json_style != JsonStyle::lines {
+            return Err(anyhow!(
+                "Cannot append to format=json with json_style={0:?}.",
+                args.json_style
+            ));
+        } else if let OutputWriter::backend = args.writer {
+            return Err(anyhow!("--append is only supported with --writer=arrow."));
+        }
+    }
+
+    if let (OutputWriter::backend, Some(_)) = (args.writer, to_compression) {
+        return Err(anyhow!(
+            "Compressed output is only supported with --writer=arrow."
+        ));
+    }
+
+    // args.json_style
+    debug!("args.json_style = {0:?}", &args.json_style);
+    if let OutputWriter::backend = args.writer {
+        if format == OutputFormat::json && args.json_style != JsonStyle::lines {
+            return Err(anyhow!(
+                "json_style={0:?} is only supported with --writer=arrow.",
+                args.json_style
+            ));
+        }
+    }
+
+    let mut output_csv_options = CsvOptions {
+        delimiter: args.output_delimiter,
+        quote: args.output_quote,
+        escape: args.output_escape,
+        header: !args.output_no_header,
+        null_value: args.output_null_value,
+        skip_rows: 0,
+    };
+    if format == OutputFormat::tsv && output_csv_options.delimiter.is_none() {
+        output_csv_options.delimiter = Some(b'\t');
+    }
+    if let OutputWriter::backend = args.writer {
+        if format == OutputFormat::csv && output_csv_options != CsvOptions::default() {
+            return Err(anyhow!(
+                "The csv output options are only supported with --writer=arrow."
+            ));
+        }
+    }
+
+    let output_options = OutputOptions {
+        append: args.append,
+        duckdb_extension_dir: args.duckdb_extension_dir.clone(),
+        json_style: args.json_style,
+        csv: output_csv_options,
+        parquet: ParquetOptions {
+            compression: args.parquet_compression,
+            row_group_size: args.row_group_size,
+            statistics: args.parquet_statistics,
+            dictionary: args.parquet_dictionary,
+        },
+    };
+    debug!("output_options = {output_options:?}");
+
     // backend
     debug!("args.backend = {0:?}", &args.backend);
     let mut backend: Backend = args.backend;
 
     // database
-    let mut database: String;
-
-    debug!("args.database = {0:?}", &args.database);
-    if let Some(args_database) = args.database {
-        database = args_database;
-        if backend == Backend::auto {
-            if database.starts_with("duckdb://") {
-                backend = Backend::duckdb;
-            } else {
-                // FIXME: Replace this with connectorx when implemented
-                backend = Backend::duckdb;
+    debug!(
+        "args.database = {}",
+        database::redact(&format!("{:?}", args.database))
+    );
+    let mut database: Option<DatabaseSpec> = None;
+    let mut attached = AttachedDatabases::new();
+    for arg in args.database.iter() {
+        match database::parse_database_arg(arg)? {
+            (Some(alias), _) if attached.iter().any(|(a, _)| *a == alias) => {
+                return Err(anyhow!("The database alias {alias:?} is given more than once."));
+            }
+            (Some(alias), spec) => attached.push((alias, spec)),
+            (None, spec) if database.is_none() => database = Some(spec),
+            (None, _) => {
+                return Err(anyhow!(
+                    "Only one --database can be given without an alias. Use --database <alias>=<uri> to attach others."
+                ))
             }
         }
-    } else {
-        database = String::from("");
-        if backend == Backend::auto {
-            backend = DEFAULT_BACKEND;
+    }
+    if backend == Backend::auto {
+        backend = match &database {
+            // only the duckdb backend can attach databases other than sqlite ones
+            _ if !attached.is_empty() => {
+                let all_sqlite = database
+                    .iter()
+                    .chain(attached.iter().map(|(_, spec)| spec))
+                    .all(|spec| spec.scheme == DatabaseScheme::Sqlite);
+                if all_sqlite {
+                    Backend::sqlite
+                } else {
+                    Backend::duckdb
+                }
+            }
+            Some(database) => match database.scheme {
+                DatabaseScheme::DuckDb => Backend::duckdb,
+                DatabaseScheme::Sqlite => Backend::sqlite,
+                // push the query down to Postgres unless files are joined
+                // with its tables
+                DatabaseScheme::Postgres
+                    if sources.iter().all(|spec| is_database_table(&spec.source)) =>
+                {
+                    Backend::postgres
+                }
+                // FIXME: Replace this with connectorx when implemented
+                DatabaseScheme::Postgres => Backend::duckdb,
+            },
+            #[cfg(feature = "duckdb")]
+            None if format == OutputFormat::database => Backend::duckdb,
+            #[cfg(all(feature = "sqlite", not(feature = "duckdb")))]
+            None if format == OutputFormat::database => Backend::sqlite,
+            None => DEFAULT_BACKEND,
+        };
+    }
+    // args.read_only and args.allow_writes
+    let allow_writes = args.allow_writes && !args.read_only;
+    debug!("allow_writes = {allow_writes:?}");
+    if !allow_writes {
+        for spec in database
+            .iter_mut()
+            .chain(attached.iter_mut().map(|(_, spec)| spec))
+        {
+            spec.read_only = true;
         }
     }
+    let any_read_only = database
+        .iter()
+        .chain(attached.iter().map(|(_, spec)| spec))
+        .any(|spec| spec.read_only);
+    if args.sql && !args.no_exec && any_read_only {
+        database::check_read_only_sql(&query)?;
+    }
     debug!("database = {database:?}");
+    debug!("attached = {attached:?}");
     debug!("backend = {backend:?}");
 
+    if !attached.is_empty() && backend != Backend::duckdb && backend != Backend::sqlite {
+        return Err(anyhow!(
+            "Attaching databases is only supported by the duckdb and sqlite backends."
+        ));
+    }
+
+    if format == OutputFormat::database && backend == Backend::datafusion {
+        return Err(anyhow!(
+            "Writing to a database table is not supported by the datafusion backend."
+        ));
+    }
+
     // writer
     debug!("args.writer = {0:?}", &args.writer);
 
-    if args.no_exec || (database == "" && args.from.len() == 0 && !args.sql) {
+    if args.no_exec
+        || (database.is_none() && attached.is_empty() && args.from.is_empty() && !args.sql)
+    {
         let sql = get_sql_from_query(&query)?;
-        println!("{}", &sql);
+        println!("{}", database::redact(&sql));
     } else {
         let mut found_backend = false;
 
@@ -243,15 +777,52 @@ fn main() -> Result<()> {
                 &query,
                 &sources,
                 &to,
-                &database,
+                database.as_ref(),
                 &format,
                 &args.writer,
+                &output_options,
             ))?;
             found_backend = true;
         }
         #[cfg(feature = "duckdb")]
         if backend == Backend::duckdb {
-            backends::duckdb::query(&query, &sources, &to, &database, &format, &args.writer)?;
+            backends::duckdb::query(
+                &query,
+                &sources,
+                &to,
+                database.as_ref(),
+                &attached,
+                &format,
+                &args.writer,
+                &output_options,
+            )?;
+            found_backend = true;
+        }
+        #[cfg(feature = "postgres")]
+        if backend == Backend::postgres {
+            backends::postgres::query(
+                &query,
+                &sources,
+                &to,
+                database.as_ref(),
+                &format,
+                &args.writer,
+                &output_options,
+            )?;
+            found_backend = true;
+        }
+        #[cfg(feature = "sqlite")]
+        if backend == Backend::sqlite {
+            backends::sqlite::query(
+                &query,
+                &sources,
+                &to,
+                database.as_ref(),
+                &attached,
+                &format,
+                &args.writer,
+                &output_options,
+            )?;
             found_backend = true;
         }
         if !found_backend {
@@ -264,15 +835,139 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn get_dest_from_to(to: &str) -> Result<Box<dyn Write>> {
+fn get_format_from_extension(fileext: &str) -> Option<OutputFormat> {
+    let format = match fileext {
+        "csv" => OutputFormat::csv,
+        "tsv" => OutputFormat::tsv,
+        "json" => OutputFormat::json,
+        "parquet" => OutputFormat::parquet,
+        "table" | "tbl" => OutputFormat::table,
+        "arrow" | "feather" => OutputFormat::arrow,
+        "arrows" => OutputFormat::arrows,
+        "md" | "markdown" => OutputFormat::markdown,
+        "html" | "htm" => OutputFormat::html,
+        "tex" => OutputFormat::latex,
+        "xlsx" => OutputFormat::xlsx,
+        _ => return None,
+    };
+    Some(format)
+}
+
+fn get_dest_from_to(to: &str, append: bool) -> Result<compression::Encoder> {
     // determine the destination
-    let mut dest: Box<dyn Write>;
-    if to == "-" {
-        dest = Box::new(std::io::stdout());
+    let dest: Box<dyn Write> = if to == "-" {
+        Box::new(std::io::stdout())
+    } else if append {
+        Box::new(fs::OpenOptions::new().create(true).append(true).open(to)?)
     } else {
-        dest = Box::new(std::fs::File::create(&to)?);
+        Box::new(std::fs::File::create(to)?)
+    };
+    let (_, compression) = compression::split_path(to);
+    compression::get_encoder(compression, dest)
+}
+
+/// Checks the header of an existing CSV file against the columns about to be
+/// appended to it and returns whether a header still needs to be written.
+fn check_csv_append_header(to: &str, columns: &[String], options: &CsvOptions) -> Result<bool> {
+    let file = match fs::File::open(to) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = String::new();
+    io::BufReader::new(file).read_line(&mut header)?;
+    let header = header.trim_end_matches(['\r', '\n']);
+    if header.is_empty() {
+        return Ok(true);
+    }
+    let existing = delimited::parse_header(header, to, options)?;
+    if existing != columns {
+        return Err(anyhow!(
+            "Cannot append to {to:?}: its columns {existing:?} do not match {columns:?}."
+        ));
     }
-    Ok(dest)
+    Ok(false)
+}
+
+/// Returns the path of a new part file in the parquet dataset directory `to`,
+/// creating the directory if required, along with an existing part file (if
+/// any) to check the schema against.
+fn get_parquet_append_paths(to: &str) -> Result<(String, Option<String>)> {
+    let dir = Utf8Path::new(to);
+    if dir.is_file() {
+        return Err(anyhow!(
+            "Cannot append to the single parquet file {to:?}. Use a directory dataset instead."
+        ));
+    }
+    fs::create_dir_all(dir)?;
+    let mut parts: Vec<String> = Vec::new();
+    for entry in dir.read_dir_utf8()? {
+        let path = entry?.path().to_path_buf();
+        if path.extension() == Some("parquet") {
+            parts.push(path.to_string());
+        }
+    }
+    parts.sort();
+    let mut n = parts.len();
+    while dir.join(format!("part-{n}.parquet")).exists() {
+        n += 1;
+    }
+    let part = dir.join(format!("part-{n}.parquet")).to_string();
+    debug!("part = {part:?}");
+    Ok((part, parts.into_iter().next()))
+}
+
+/// Returns a path in the temporary directory for a file derived from `name`,
+/// which is unique to this process and call, since different sources can
+/// have the same file name.
+fn get_temp_path(name: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir()
+        .join(format!("pq-{}-{n}-{name}", std::process::id()))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Temporary files of a query (e.g. decompressed or staged sources), which
+/// are removed when it goes out of scope, whether or not the query failed.
+#[derive(Default)]
+struct TempFiles(Vec<String>);
+
+impl TempFiles {
+    fn push(&mut self, path: String) {
+        self.0.push(path);
+    }
+
+    fn last(&self) -> Option<&String> {
+        self.0.last()
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in self.0.iter() {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Could not remove the temporary file {path:?}: {e}");
+            }
+        }
+    }
+}
+
+/// Parses a parquet compression codec. A level (e.g. `zstd:3`) is rejected
+/// rather than ignored, since the parquet writer doesn't support levels.
+fn parse_parquet_compression(s: &str) -> Result<ParquetCompression> {
+    let (codec, level) = match s.split_once(':') {
+        Some((codec, level)) => (codec, Some(level)),
+        None => (s, None),
+    };
+    let compression = ParquetCompression::from_str(codec, true).map_err(|e| anyhow!(e))?;
+    if let Some(level) = level {
+        return Err(anyhow!(
+            "Compression levels are not supported by the parquet writer. Use {codec:?} without the level {level:?}."
+        ));
+    }
+    Ok(compression)
 }
 
 fn get_sql_from_query(query: &str) -> Result<String> {
@@ -284,15 +979,46 @@ fn get_sql_from_query(query: &str) -> Result<String> {
     Ok(sql)
 }
 
-fn standardise_sources(from: &FromType) -> Result<SourcesType> {
+fn standardise_sources(from: &FromType, defaults: &SourceSpec) -> Result<SourcesType> {
     debug!("from={from:?}");
     let supported_file_types: HashSet<&str> = HashSet::from(SUPPORTED_FILE_TYPES);
     // let mut sources : Vec<(String, String)> = Vec::<(String, String)>::new();
     let mut sources: SourcesType = SourcesType::new();
     for fromstr in from.iter() {
+        // split off any per-source options, e.g. `sales.csv?delimiter=;`
+        let (fromstr, options) = match fromstr.split_once('?') {
+            Some((fromstr, options)) => (fromstr, options),
+            None => (fromstr.as_str(), ""),
+        };
         let mut fromparts: Vec<String> = fromstr.split("=").map(|s| s.to_string()).collect();
-        if fromparts.len() == 1 {
-            let filepath = Utf8Path::new(&fromparts[0]);
+        if let Some((path, Some("*"))) = xlsx::parse_source(fromparts.last().unwrap()) {
+            // Register each sheet of the workbook as its own table
+            if fromparts.len() > 1 {
+                return Err(anyhow!(
+                    "Cannot use an alias when reading all the sheets of {path:?}."
+                ));
+            }
+            for sheet in xlsx::get_sheet_names(path)? {
+                sources.push(get_source_spec(
+                    &sheet.replace(" ", "_"),
+                    &format!("{path}#{sheet}"),
+                    options,
+                    defaults,
+                )?);
+            }
+            continue;
+        }
+        if fromparts.len() == 1 && fromparts[0].starts_with('-') {
+            // Reading from stdin
+            fromparts = vec![String::from("stdin"), fromparts[0].clone()];
+        } else if let (1, Some((_, Some(sheet)))) =
+            (fromparts.len(), xlsx::parse_source(&fromparts[0]))
+        {
+            // Dealing with a sheet of a workbook
+            fromparts = vec![sheet.replace(" ", "_"), fromparts[0].clone()];
+        } else if fromparts.len() == 1 {
+            // ignore any compression extension, e.g. of `invoices.csv.gz`
+            let filepath = Utf8Path::new(compression::split_path(&fromparts[0]).0);
             let fileext = filepath
                 .extension()
                 .ok_or(anyhow!("No extension in: {filepath}"))?;
@@ -300,7 +1026,7 @@ fn standardise_sources(from: &FromType) -> Result<SourcesType> {
                 // Dealing with a file
                 let last_component = filepath
                     .components()
-                    .last()
+                    .next_back()
                     .ok_or(anyhow!("There was no last component of: {filepath}"))?;
                 let filename = last_component
                     .as_str()
@@ -316,8 +1042,116 @@ fn standardise_sources(from: &FromType) -> Result<SourcesType> {
                 fromparts = vec![tablename.to_string(), fromparts[0].clone()];
             }
         }
-        sources.push((fromparts[0].clone(), fromparts[1].clone()));
+        if get_stdin_format(&fromparts[1])?.is_some()
+            && sources
+                .iter()
+                .any(|spec| get_stdin_format(&spec.source).is_ok_and(|f| f.is_some()))
+        {
+            return Err(anyhow!("Only one source can be read from stdin."));
+        }
+        sources.push(get_source_spec(
+            &fromparts[0],
+            &fromparts[1],
+            options,
+            defaults,
+        )?);
     }
     debug!("sources={sources:?}");
     Ok(sources)
 }
+
+/// Returns the spec of a source with its options given as URL-style query
+/// parameters (e.g. `delimiter=;&header=false`) applied on top of the
+/// options from the command line.
+fn get_source_spec(
+    alias: &str,
+    source: &str,
+    options: &str,
+    defaults: &SourceSpec,
+) -> Result<SourceSpec> {
+    let mut spec = SourceSpec {
+        alias: alias.to_string(),
+        source: source.to_string(),
+        ..defaults.clone()
+    };
+    let mut has_csv_options = false;
+    let mut has_text_options = false;
+    for (key, value) in url::form_urlencoded::parse(options.as_bytes()) {
+        let is_text_option = ["schema", "infer_rows", "encoding"].contains(&key.as_ref());
+        has_csv_options |= key != "columns" && !is_text_option;
+        has_text_options |= is_text_option;
+        match key.as_ref() {
+            "delimiter" => spec.csv.delimiter = Some(delimited::parse_char(&value)?),
+            "quote" => spec.csv.quote = delimited::parse_char(&value)?,
+            "escape" => spec.csv.escape = Some(delimited::parse_char(&value)?),
+            "header" => {
+                spec.csv.header = value
+                    .parse()
+                    .map_err(|_| anyhow!("Expected true or false for header, got {value:?}."))?
+            }
+            "null_value" => spec.csv.null_value = Some(value.to_string()),
+            "skip_rows" => {
+                spec.csv.skip_rows = value
+                    .parse()
+                    .map_err(|_| anyhow!("Expected a number for skip_rows, got {value:?}."))?
+            }
+            "columns" => {
+                spec.columns = Some(value.split(',').map(|c| c.trim().to_string()).collect())
+            }
+            "encoding" => spec.encoding = encoding::parse_encoding(&value)?,
+            "schema" => spec.schema = Some(schema::parse_schema(&value)?),
+            "infer_rows" => {
+                spec.infer_rows = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("Expected a number for infer_rows, got {value:?}."))?,
+                )
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unknown option {key:?} for source {source:?}. Supported options are {SUPPORTED_SOURCE_OPTIONS:?}."
+                ))
+            }
+        }
+    }
+    let (uncompressed, _) = compression::split_path(source);
+    let is_csv = uncompressed.ends_with("csv") || uncompressed.ends_with("tsv");
+    if has_csv_options && !is_csv {
+        return Err(anyhow!(
+            "The csv options given for source {source:?} only apply to csv and tsv sources."
+        ));
+    }
+    let is_json = uncompressed.ends_with("json");
+    if has_text_options && !is_csv && !is_json {
+        return Err(anyhow!(
+            "The schema, infer_rows and encoding options given for source {source:?} only apply to csv, tsv and json sources."
+        ));
+    }
+    Ok(spec)
+}
+
+/// Whether the source is a table (or view) of the database rather than a
+/// file or stdin
+fn is_database_table(source: &str) -> bool {
+    let filepath = Utf8Path::new(compression::split_path(source).0);
+    let fileext = filepath.extension().unwrap_or("");
+    !source.starts_with('-')
+        && xlsx::parse_source(source).is_none()
+        && !SUPPORTED_FILE_TYPES.contains(&fileext)
+}
+
+/// Returns the format of a source that is read from stdin, e.g. `-:csv`, or
+/// `None` if the source is not stdin. Without a format an Arrow IPC stream is
+/// expected.
+fn get_stdin_format(source: &str) -> Result<Option<&str>> {
+    if source == "-" {
+        return Ok(Some("arrows"));
+    }
+    match source.strip_prefix("-:") {
+        Some(format) if SUPPORTED_STDIN_FORMATS.contains(&format) => Ok(Some(format)),
+        Some(format) => Err(anyhow!(
+            "Reading format={format:?} from stdin is not supported. Supported formats are {SUPPORTED_STDIN_FORMATS:?}."
+        )),
+        None => Ok(None),
+    }
+}
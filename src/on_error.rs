@@ -0,0 +1,200 @@
+//! Handling of malformed rows in csv and json sources (`--on-error`).
+//!
+//! Unless the policy is to fail, sources are split into rows, which are
+//! checked by parsing them with the schema of the source. Rows are parsed in
+//! chunks and only the rows of chunks that fail are parsed one by one, so
+//! that sources without malformed rows are only parsed once more.
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+/// The number of rows that are checked together
+const CHUNK_SIZE: usize = 1024;
+
+/// Whether rows have been written to the quarantine file by this process, in
+/// which case further rows are appended to it rather than replacing it
+static QUARANTINE_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnError {
+    Fail,
+    Skip,
+    /// Skip the rows and write them to the file at the path
+    Quarantine(String),
+}
+
+/// Parses `fail`, `skip` or `quarantine=<path>`
+pub fn parse_on_error(s: &str) -> Result<OnError> {
+    match s.split_once('=') {
+        Some(("quarantine", path)) if !path.is_empty() => Ok(OnError::Quarantine(path.to_string())),
+        None if s == "fail" => Ok(OnError::Fail),
+        None if s == "skip" => Ok(OnError::Skip),
+        _ => Err(anyhow!(
+            "Expected fail, skip or quarantine=<path>, got {s:?}."
+        )),
+    }
+}
+
+/// A row of a source, as read and as normalised for the arrow readers
+#[derive(Debug)]
+pub struct Row {
+    /// The line of the source on which the row starts
+    pub line: usize,
+    pub record: String,
+    pub data: Vec<u8>,
+}
+
+/// A row that was skipped and why
+#[derive(Debug)]
+pub struct Rejected {
+    pub line: usize,
+    pub record: String,
+    pub reason: String,
+}
+
+/// The rows of a source, together with its (normalised) header, if any
+#[derive(Debug, Default)]
+pub struct Rows {
+    pub header: Vec<u8>,
+    pub rows: Vec<Row>,
+}
+
+impl Rows {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.header.clone();
+        for row in self.rows.iter() {
+            buf.extend_from_slice(&row.data);
+        }
+        buf
+    }
+
+    /// Keeps the rows that `validate` accepts and returns the others.
+    /// `validate` is given the header followed by one or more rows.
+    pub fn retain_valid(&mut self, validate: impl Fn(&[u8]) -> Result<()>) -> Vec<Rejected> {
+        let mut rejected: Vec<Rejected> = Vec::new();
+        let mut valid: Vec<Row> = Vec::new();
+        let mut rows = std::mem::take(&mut self.rows).into_iter().peekable();
+        while rows.peek().is_some() {
+            let chunk: Vec<Row> = rows.by_ref().take(CHUNK_SIZE).collect();
+            if validate(&self.get_bytes(&chunk)).is_ok() {
+                valid.extend(chunk);
+                continue;
+            }
+            for row in chunk {
+                match validate(&self.get_bytes(std::slice::from_ref(&row))) {
+                    Ok(()) => valid.push(row),
+                    Err(e) => rejected.push(Rejected {
+                        line: row.line,
+                        record: row.record,
+                        reason: e.to_string(),
+                    }),
+                }
+            }
+        }
+        self.rows = valid;
+        rejected
+    }
+
+    fn get_bytes(&self, rows: &[Row]) -> Vec<u8> {
+        let mut buf = self.header.clone();
+        for row in rows {
+            buf.extend_from_slice(&row.data);
+        }
+        buf
+    }
+}
+
+/// Splits newline delimited json into its rows, rejecting the lines that
+/// aren't json objects.
+pub fn split_ndjson(buf: &[u8]) -> (Rows, Vec<Rejected>) {
+    let mut rows = Rows::default();
+    let mut rejected: Vec<Rejected> = Vec::new();
+    for (i, line) in buf.split(|b| *b == b'\n').enumerate() {
+        let record = String::from_utf8_lossy(line).trim_end().to_string();
+        if record.is_empty() {
+            continue;
+        }
+        let reason = match serde_json::from_slice::<Value>(line) {
+            Ok(value) if value.is_object() => None,
+            Ok(_) => Some(String::from("Expected a json object")),
+            Err(e) => Some(e.to_string()),
+        };
+        match reason {
+            Some(reason) => rejected.push(Rejected {
+                line: i + 1,
+                record,
+                reason,
+            }),
+            None => rows.rows.push(Row {
+                line: i + 1,
+                data: [line, b"\n"].concat(),
+                record,
+            }),
+        }
+    }
+    (rows, rejected)
+}
+
+/// Reports the rows of `source` that were skipped: a count on stderr and,
+/// when quarantining, the rows themselves in the quarantine file.
+pub fn report(source: &str, rejected: &[Rejected], on_error: &OnError) -> Result<()> {
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    match on_error {
+        OnError::Fail => {
+            let first = &rejected[0];
+            return Err(anyhow!(
+                "Malformed row on line {} of {source:?}: {}",
+                first.line,
+                first.reason
+            ));
+        }
+        OnError::Skip => {
+            eprintln!("Skipped {} malformed rows of {source:?}.", rejected.len());
+        }
+        OnError::Quarantine(path) => {
+            let started = QUARANTINE_STARTED.swap(true, Ordering::SeqCst);
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(started)
+                .truncate(!started)
+                .open(path)?;
+            let mut writer = csv::Writer::from_writer(file);
+            if !started {
+                writer.write_record(["source", "line", "reason", "record"])?;
+            }
+            for r in rejected {
+                writer.write_record([source, &r.line.to_string(), &r.reason, &r.record])?;
+            }
+            writer.flush()?;
+            eprintln!(
+                "Skipped {} malformed rows of {source:?}, which were written to {path:?}.",
+                rejected.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of null (or missing) values of each of the fields in
+/// rows of newline delimited json, for checking that none of the other
+/// values were read as nulls because they don't match the type of their
+/// column.
+pub fn count_json_nulls(buf: &[u8], fields: &[&str]) -> Vec<usize> {
+    let mut counts = vec![0; fields.len()];
+    for line in buf.split(|b| *b == b'\n') {
+        if let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(line) {
+            for (count, field) in counts.iter_mut().zip(fields) {
+                if object.get(*field).map_or(true, |value| value.is_null()) {
+                    *count += 1;
+                }
+            }
+        }
+    }
+    counts
+}
@@ -0,0 +1,229 @@
+//! Printing of the metadata of parquet files (`pq parquet-meta`).
+
+use std::fs;
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+// Use the parquet and arrow crates of whichever backend is available
+#[cfg(all(feature = "datafusion", not(feature = "arrow")))]
+use datafusion::{arrow, parquet};
+
+use arrow::array::{ArrayRef, StringArray};
+use arrow::record_batch::RecordBatch;
+use arrow::util::pretty::pretty_format_batches;
+use parquet::file::metadata::{ColumnChunkMetaData, ParquetMetaData};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::statistics::Statistics;
+
+/// The maximum length of key-value metadata values in tables, since these can
+/// be long (e.g. the serialized Arrow schema)
+const MAX_TABLE_VALUE_LENGTH: usize = 60;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[allow(non_camel_case_types)]
+pub enum MetadataFormat {
+    table,
+    json,
+}
+
+pub fn print_metadata(path: &str, format: &MetadataFormat) -> Result<()> {
+    let reader = SerializedFileReader::new(fs::File::open(path)?)?;
+    let metadata = reader.metadata();
+    let mut dest = std::io::stdout();
+    match format {
+        MetadataFormat::table => write_tables(path, metadata, &mut dest),
+        MetadataFormat::json => {
+            serde_json::to_writer_pretty(&mut dest, &get_json(path, metadata))?;
+            dest.write_all(b"\n")?;
+            Ok(())
+        }
+    }
+}
+
+fn get_json(path: &str, metadata: &ParquetMetaData) -> Value {
+    let file_metadata = metadata.file_metadata();
+    let key_value_metadata: serde_json::Map<String, Value> = file_metadata
+        .key_value_metadata()
+        .map(|kvs| {
+            kvs.iter()
+                .map(|kv| (kv.key.clone(), json!(kv.value)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let row_groups: Vec<Value> = metadata
+        .row_groups()
+        .iter()
+        .enumerate()
+        .map(|(i, row_group)| {
+            let columns: Vec<Value> = row_group
+                .columns()
+                .iter()
+                .map(|column| {
+                    let statistics = column.statistics();
+                    json!({
+                        "column": column.column_path().string(),
+                        "physical_type": column.column_type().to_string(),
+                        "logical_type": column.column_descr().logical_type().map(|t| format!("{t:?}")),
+                        "compression": column.compression().to_string(),
+                        "encodings": column.encodings().iter().map(|e| e.to_string()).collect::<Vec<String>>(),
+                        "num_values": column.num_values(),
+                        "compressed_size": column.compressed_size(),
+                        "uncompressed_size": column.uncompressed_size(),
+                        "null_count": statistics.map(|s| s.null_count()),
+                        "distinct_count": statistics.and_then(|s| s.distinct_count()),
+                        "min": statistics.and_then(|s| get_min_max(s).map(|(min, _)| min)),
+                        "max": statistics.and_then(|s| get_min_max(s).map(|(_, max)| max)),
+                    })
+                })
+                .collect();
+            json!({
+                "row_group": i,
+                "num_rows": row_group.num_rows(),
+                "total_byte_size": row_group.total_byte_size(),
+                "columns": columns,
+            })
+        })
+        .collect();
+    json!({
+        "path": path,
+        "version": file_metadata.version(),
+        "num_rows": file_metadata.num_rows(),
+        "created_by": file_metadata.created_by(),
+        "key_value_metadata": key_value_metadata,
+        "row_groups": row_groups,
+    })
+}
+
+fn write_tables(path: &str, metadata: &ParquetMetaData, dest: &mut dyn Write) -> Result<()> {
+    let file_metadata = metadata.file_metadata();
+    let mut properties: Vec<(String, String)> = vec![
+        (String::from("path"), path.to_string()),
+        (String::from("version"), file_metadata.version().to_string()),
+        (
+            String::from("num_rows"),
+            file_metadata.num_rows().to_string(),
+        ),
+        (
+            String::from("created_by"),
+            file_metadata.created_by().unwrap_or_default().to_string(),
+        ),
+        (
+            String::from("num_row_groups"),
+            metadata.num_row_groups().to_string(),
+        ),
+    ];
+    for kv in file_metadata.key_value_metadata().into_iter().flatten() {
+        let mut value = kv.value.clone().unwrap_or_default();
+        if value.chars().count() > MAX_TABLE_VALUE_LENGTH {
+            value = value
+                .chars()
+                .take(MAX_TABLE_VALUE_LENGTH)
+                .collect::<String>()
+                + "...";
+        }
+        properties.push((kv.key.clone(), value));
+    }
+    let (keys, values): (Vec<String>, Vec<String>) = properties.into_iter().unzip();
+    write_table(vec![("key", keys), ("value", values)], dest)?;
+
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for (i, row_group) in metadata.row_groups().iter().enumerate() {
+        for column in row_group.columns() {
+            rows.push(get_column_chunk_row(i, row_group.num_rows(), column));
+        }
+    }
+    let headers = [
+        "row_group",
+        "num_rows",
+        "column",
+        "type",
+        "compression",
+        "encodings",
+        "compressed_size",
+        "uncompressed_size",
+        "null_count",
+        "min",
+        "max",
+    ];
+    let columns = headers
+        .iter()
+        .enumerate()
+        .map(|(j, header)| (*header, rows.iter().map(|row| row[j].clone()).collect()))
+        .collect();
+    write_table(columns, dest)
+}
+
+fn get_column_chunk_row(
+    row_group: usize,
+    num_rows: i64,
+    column: &ColumnChunkMetaData,
+) -> Vec<String> {
+    let statistics = column.statistics();
+    let (min, max) = statistics.and_then(get_min_max).unwrap_or_default();
+    let column_type = match column.column_descr().logical_type() {
+        Some(logical_type) => format!("{} ({logical_type:?})", column.column_type()),
+        None => column.column_type().to_string(),
+    };
+    vec![
+        row_group.to_string(),
+        num_rows.to_string(),
+        column.column_path().string(),
+        column_type,
+        column.compression().to_string(),
+        column
+            .encodings()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+        column.compressed_size().to_string(),
+        column.uncompressed_size().to_string(),
+        statistics.map_or(String::new(), |s| s.null_count().to_string()),
+        min,
+        max,
+    ]
+}
+
+/// Returns the min and max statistics as strings, decoding byte arrays as
+/// UTF-8 where possible.
+fn get_min_max(statistics: &Statistics) -> Option<(String, String)> {
+    if !statistics.has_min_max_set() {
+        return None;
+    }
+    let bytes_to_string = |bytes: &[u8]| match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => format!("{bytes:?}"),
+    };
+    let min_max = match statistics {
+        Statistics::Boolean(s) => (s.min().to_string(), s.max().to_string()),
+        Statistics::Int32(s) => (s.min().to_string(), s.max().to_string()),
+        Statistics::Int64(s) => (s.min().to_string(), s.max().to_string()),
+        Statistics::Int96(s) => (s.min().to_string(), s.max().to_string()),
+        Statistics::Float(s) => (s.min().to_string(), s.max().to_string()),
+        Statistics::Double(s) => (s.min().to_string(), s.max().to_string()),
+        Statistics::ByteArray(s) => (
+            bytes_to_string(s.min().data()),
+            bytes_to_string(s.max().data()),
+        ),
+        Statistics::FixedLenByteArray(s) => (
+            bytes_to_string(s.min().data()),
+            bytes_to_string(s.max().data()),
+        ),
+    };
+    Some(min_max)
+}
+
+fn write_table(columns: Vec<(&str, Vec<String>)>, dest: &mut dyn Write) -> Result<()> {
+    let (names, arrays): (Vec<&str>, Vec<ArrayRef>) = columns
+        .into_iter()
+        .map(|(name, values)| (name, Arc::new(StringArray::from(values)) as ArrayRef))
+        .unzip();
+    let rb = RecordBatch::try_from_iter(names.into_iter().zip(arrays))?;
+    dest.write_all(pretty_format_batches(&[rb])?.to_string().as_bytes())?;
+    dest.write_all(b"\n")?;
+    Ok(())
+}
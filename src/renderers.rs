@@ -0,0 +1,154 @@
+//! Renderers for the markdown, html and latex table output formats.
+//!
+//! These work on the string representation of the results so that they can be
+//! shared by the backends regardless of the version of arrow they use.
+
+use std::io::prelude::*;
+
+use anyhow::Result;
+
+/// The results of a query rendered to strings
+pub struct TextTable {
+    pub columns: Vec<String>,
+    /// Whether each column holds numeric values, which are right aligned
+    pub numeric: Vec<bool>,
+    /// The values of each row, with `None` for nulls
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl TextTable {
+    pub fn write_markdown(&self, dest: &mut dyn Write) -> Result<()> {
+        let header: Vec<String> = self.columns.iter().map(|c| escape_markdown(c)).collect();
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|v| v.as_deref().map_or(String::new(), escape_markdown))
+                    .collect()
+            })
+            .collect();
+
+        // pad the columns to a common width so the source is readable too
+        let widths: Vec<usize> = (0..header.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain([header[i].chars().count(), 3])
+                    .max()
+                    .unwrap_or(3)
+            })
+            .collect();
+        let pad = |i: usize, value: &str| -> String {
+            if self.numeric[i] {
+                format!("{value:>width$}", width = widths[i])
+            } else {
+                format!("{value:<width$}", width = widths[i])
+            }
+        };
+
+        let cells: Vec<String> = header.iter().enumerate().map(|(i, c)| pad(i, c)).collect();
+        writeln!(dest, "| {} |", cells.join(" | "))?;
+        let rules: Vec<String> = widths
+            .iter()
+            .enumerate()
+            .map(|(i, width)| match self.numeric[i] {
+                true => format!("{}:", "-".repeat(width - 1)),
+                false => format!(":{}", "-".repeat(width - 1)),
+            })
+            .collect();
+        writeln!(dest, "| {} |", rules.join(" | "))?;
+        for row in rows.iter() {
+            let cells: Vec<String> = row.iter().enumerate().map(|(i, v)| pad(i, v)).collect();
+            writeln!(dest, "| {} |", cells.join(" | "))?;
+        }
+        Ok(())
+    }
+
+    pub fn write_html(&self, dest: &mut dyn Write) -> Result<()> {
+        let align = |i: usize| match self.numeric[i] {
+            true => " style=\"text-align: right\"",
+            false => "",
+        };
+
+        writeln!(dest, "<table>")?;
+        writeln!(dest, "  <thead>")?;
+        writeln!(dest, "    <tr>")?;
+        for (i, column) in self.columns.iter().enumerate() {
+            writeln!(dest, "      <th{}>{}</th>", align(i), escape_html(column))?;
+        }
+        writeln!(dest, "    </tr>")?;
+        writeln!(dest, "  </thead>")?;
+        writeln!(dest, "  <tbody>")?;
+        for row in self.rows.iter() {
+            writeln!(dest, "    <tr>")?;
+            for (i, value) in row.iter().enumerate() {
+                let value = value.as_deref().map_or(String::new(), escape_html);
+                writeln!(dest, "      <td{}>{}</td>", align(i), value)?;
+            }
+            writeln!(dest, "    </tr>")?;
+        }
+        writeln!(dest, "  </tbody>")?;
+        writeln!(dest, "</table>")?;
+        Ok(())
+    }
+
+    pub fn write_latex(&self, dest: &mut dyn Write) -> Result<()> {
+        let spec: String = self
+            .numeric
+            .iter()
+            .map(|numeric| if *numeric { 'r' } else { 'l' })
+            .collect();
+        let header: Vec<String> = self.columns.iter().map(|c| escape_latex(c)).collect();
+
+        writeln!(dest, "\\begin{{tabular}}{{{spec}}}")?;
+        writeln!(dest, "\\hline")?;
+        writeln!(dest, "{} \\\\", header.join(" & "))?;
+        writeln!(dest, "\\hline")?;
+        for row in self.rows.iter() {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|v| v.as_deref().map_or(String::new(), escape_latex))
+                .collect();
+            writeln!(dest, "{} \\\\", cells.join(" & "))?;
+        }
+        writeln!(dest, "\\hline")?;
+        writeln!(dest, "\\end{{tabular}}")?;
+        Ok(())
+    }
+}
+
+fn escape_markdown(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn escape_latex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
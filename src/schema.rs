@@ -0,0 +1,141 @@
+//! Schemas given for csv and json sources, which override the column types
+//! that the backends would otherwise infer.
+
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+// Use the arrow crate of whichever backend is available
+#[cfg(all(feature = "datafusion", not(feature = "arrow")))]
+use datafusion::arrow;
+
+use arrow::datatypes::DataType;
+use arrow::ipc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnType {
+    Boolean,
+    Int32,
+    Int64,
+    Float32,
+    Float64,
+    Decimal(u8, u8),
+    Utf8,
+    Date,
+    Timestamp,
+}
+
+/// The names and types of the columns to override
+pub type SchemaSpec = Vec<(String, ColumnType)>;
+
+/// Parses a schema given inline as `name:type` pairs (e.g.
+/// `zip:string,id:int64`), or as the path of a json file with a list of
+/// fields (`{"fields": [{"name": "zip", "type": "string"}]}`) or of an Arrow
+/// IPC file whose schema is used.
+pub fn parse_schema(spec: &str) -> Result<SchemaSpec> {
+    if spec.ends_with(".json") {
+        let value: Value = serde_json::from_str(&fs::read_to_string(spec)?)?;
+        let fields = value
+            .get("fields")
+            .and_then(|fields| fields.as_array())
+            .ok_or(anyhow!("No list of fields found in {spec:?}."))?;
+        fields
+            .iter()
+            .map(|field| {
+                match (
+                    field.get("name").and_then(|n| n.as_str()),
+                    field.get("type").and_then(|t| t.as_str()),
+                ) {
+                    (Some(name), Some(column_type)) => {
+                        Ok((name.to_string(), parse_column_type(column_type)?))
+                    }
+                    _ => Err(anyhow!(
+                        "Expected a name and a type for each field in {spec:?}, got {field}."
+                    )),
+                }
+            })
+            .collect()
+    } else if spec.ends_with(".arrow") || spec.ends_with(".feather") {
+        let reader = ipc::reader::FileReader::try_new(fs::File::open(spec)?, None)?;
+        reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| Ok((field.name().clone(), get_column_type(field.data_type())?)))
+            .collect()
+    } else {
+        split_fields(spec)
+            .into_iter()
+            .map(|field| match field.split_once(':') {
+                Some((name, column_type)) => {
+                    Ok((name.trim().to_string(), parse_column_type(column_type)?))
+                }
+                None => Err(anyhow!(
+                    "Expected a field of the form <name>:<type>, got {field:?}."
+                )),
+            })
+            .collect()
+    }
+}
+
+/// Splits an inline schema on the commas that aren't within the parentheses
+/// of a type such as `decimal(10,2)`.
+fn split_fields(spec: &str) -> Vec<&str> {
+    let mut fields: Vec<&str> = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in spec.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                fields.push(&spec[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&spec[start..]);
+    fields
+}
+
+fn parse_column_type(s: &str) -> Result<ColumnType> {
+    let s = s.trim().to_lowercase();
+    let column_type = match s.as_str() {
+        "bool" | "boolean" => ColumnType::Boolean,
+        "int" | "int32" | "integer" => ColumnType::Int32,
+        "int64" | "bigint" | "long" => ColumnType::Int64,
+        "float" | "float32" | "real" => ColumnType::Float32,
+        "float64" | "double" => ColumnType::Float64,
+        "string" | "str" | "utf8" | "text" | "varchar" => ColumnType::Utf8,
+        "date" => ColumnType::Date,
+        "timestamp" | "datetime" => ColumnType::Timestamp,
+        _ => match s
+            .strip_prefix("decimal(")
+            .and_then(|s| s.strip_suffix(')'))
+            .and_then(|s| s.split_once(','))
+        {
+            Some((precision, scale)) => {
+                ColumnType::Decimal(precision.trim().parse()?, scale.trim().parse()?)
+            }
+            None => return Err(anyhow!("Unsupported column type {s:?}.")),
+        },
+    };
+    Ok(column_type)
+}
+
+fn get_column_type(data_type: &DataType) -> Result<ColumnType> {
+    let column_type = match data_type {
+        DataType::Boolean => ColumnType::Boolean,
+        DataType::Int8 | DataType::Int16 | DataType::Int32 => ColumnType::Int32,
+        DataType::Int64 => ColumnType::Int64,
+        DataType::Float16 | DataType::Float32 => ColumnType::Float32,
+        DataType::Float64 => ColumnType::Float64,
+        DataType::Decimal128(precision, scale) => ColumnType::Decimal(*precision, *scale),
+        DataType::Utf8 | DataType::LargeUtf8 => ColumnType::Utf8,
+        DataType::Date32 | DataType::Date64 => ColumnType::Date,
+        DataType::Timestamp(_, _) => ColumnType::Timestamp,
+        _ => return Err(anyhow!("Unsupported column type {data_type:?} in schema.")),
+    };
+    Ok(column_type)
+}
@@ -0,0 +1,271 @@
+//! Reading and writing of Excel (.xlsx) workbooks.
+//!
+//! Worksheets are exchanged with the backends as a [`Sheet`] of typed cells so
+//! that this module doesn't depend on the version of arrow a backend uses.
+
+use std::collections::HashSet;
+use std::io::prelude::*;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use calamine::{open_workbook_auto, Data, Reader};
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use rust_xlsxwriter::{Format, Workbook};
+
+/// The number of leading rows that are searched for a header row
+const HEADER_SEARCH_ROWS: usize = 20;
+
+const MILLISECONDS_PER_DAY: f64 = 86_400_000.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Empty,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+}
+
+impl Cell {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Cell::Int(i) => Some(*i as f64),
+            Cell::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_datetime(&self) -> Option<NaiveDateTime> {
+        match self {
+            Cell::Date(d) => d.and_hms_opt(0, 0, 0),
+            Cell::DateTime(dt) => Some(*dt),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Cell::Empty => None,
+            Cell::Bool(b) => Some(b.to_string()),
+            Cell::Int(i) => Some(i.to_string()),
+            Cell::Float(f) => Some(f.to_string()),
+            Cell::Text(s) => Some(s.clone()),
+            Cell::Date(d) => Some(d.to_string()),
+            Cell::DateTime(dt) => Some(dt.to_string()),
+        }
+    }
+}
+
+/// The type that all the values of a column can be represented as
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColumnType {
+    Boolean,
+    Int64,
+    Float64,
+    Date,
+    Timestamp,
+    Utf8,
+}
+
+#[derive(Debug, Default)]
+pub struct Sheet {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Sheet {
+    pub fn column_types(&self) -> Vec<ColumnType> {
+        (0..self.columns.len())
+            .map(|i| {
+                let mut column_type: Option<ColumnType> = None;
+                for cell in self.rows.iter().map(|row| &row[i]) {
+                    let cell_type = match cell {
+                        Cell::Empty => continue,
+                        Cell::Bool(_) => ColumnType::Boolean,
+                        Cell::Int(_) => ColumnType::Int64,
+                        Cell::Float(_) => ColumnType::Float64,
+                        Cell::Text(_) => ColumnType::Utf8,
+                        Cell::Date(_) => ColumnType::Date,
+                        Cell::DateTime(_) => ColumnType::Timestamp,
+                    };
+                    column_type = match (column_type, cell_type) {
+                        (None, t) => Some(t),
+                        (Some(a), b) if a == b => Some(a),
+                        (Some(ColumnType::Int64), ColumnType::Float64)
+                        | (Some(ColumnType::Float64), ColumnType::Int64) => {
+                            Some(ColumnType::Float64)
+                        }
+                        (Some(ColumnType::Date), ColumnType::Timestamp)
+                        | (Some(ColumnType::Timestamp), ColumnType::Date) => {
+                            Some(ColumnType::Timestamp)
+                        }
+                        _ => Some(ColumnType::Utf8),
+                    };
+                }
+                column_type.unwrap_or(ColumnType::Utf8)
+            })
+            .collect()
+    }
+}
+
+/// Splits an xlsx source of the form `<path>.xlsx[#<sheet>]` into the path and
+/// the optional sheet name. Returns `None` for sources that aren't xlsx files.
+pub fn parse_source(source: &str) -> Option<(&str, Option<&str>)> {
+    let (path, sheet) = match source.split_once('#') {
+        Some((path, sheet)) => (path, Some(sheet)),
+        None => (source, None),
+    };
+    if path.ends_with(".xlsx") {
+        Some((path, sheet))
+    } else {
+        None
+    }
+}
+
+pub fn get_sheet_names(path: &str) -> Result<Vec<String>> {
+    let workbook = open_workbook_auto(path)?;
+    Ok(workbook.sheet_names())
+}
+
+/// Reads a sheet (by default the first one) from the workbook at `path`.
+pub fn read_sheet(path: &str, sheet: Option<&str>) -> Result<Sheet> {
+    let mut workbook = open_workbook_auto(path)?;
+    let name = match sheet {
+        Some(name) => name.to_string(),
+        None => workbook
+            .sheet_names()
+            .first()
+            .ok_or(anyhow!("No sheets found in {path:?}."))?
+            .clone(),
+    };
+    let range = workbook.worksheet_range(&name)?;
+
+    let rows: Vec<Vec<Cell>> = range
+        .rows()
+        .map(|row| row.iter().map(get_cell).collect::<Vec<Cell>>())
+        .filter(|row| row.iter().any(|cell| *cell != Cell::Empty))
+        .collect();
+
+    // Take the first row which is as wide as the table and only holds text as
+    // the header, which skips any title rows above it.
+    let width = |row: &Vec<Cell>| row.iter().filter(|cell| **cell != Cell::Empty).count();
+    let max_width = rows
+        .iter()
+        .take(HEADER_SEARCH_ROWS)
+        .map(width)
+        .max()
+        .unwrap_or(0);
+    let header_index = rows.iter().take(HEADER_SEARCH_ROWS).position(|row| {
+        width(row) == max_width
+            && row
+                .iter()
+                .all(|cell| matches!(cell, Cell::Empty | Cell::Text(_)))
+    });
+    debug!("sheet={name:?}, header_index={header_index:?}");
+
+    let header = header_index.map(|h| rows[h].clone());
+    let mut columns: Vec<String> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    for i in 0..range.width() {
+        let mut column = header
+            .as_ref()
+            .and_then(|header| header[i].as_string())
+            .map_or(format!("column_{}", i + 1), |c| c.trim().to_string());
+        // ensure that the column names are unique
+        let base = column.clone();
+        let mut n = 1;
+        while !seen.insert(column.clone()) {
+            n += 1;
+            column = format!("{base}_{n}");
+        }
+        columns.push(column);
+    }
+
+    let rows = rows
+        .into_iter()
+        .skip(header_index.map_or(0, |h| h + 1))
+        .collect();
+
+    Ok(Sheet {
+        name,
+        columns,
+        rows,
+    })
+}
+
+fn get_cell(data: &Data) -> Cell {
+    match data {
+        Data::Empty | Data::Error(_) => Cell::Empty,
+        Data::Bool(b) => Cell::Bool(*b),
+        Data::Int(i) => Cell::Int(*i),
+        Data::Float(f) => Cell::Float(*f),
+        Data::String(s) => Cell::Text(s.clone()),
+        Data::DateTime(dt) => match get_excel_epoch().checked_add_signed(Duration::milliseconds(
+            (dt.as_f64() * MILLISECONDS_PER_DAY).round() as i64,
+        )) {
+            Some(datetime) if datetime.time() == NaiveTime::from_hms(0, 0, 0) => {
+                Cell::Date(datetime.date())
+            }
+            Some(datetime) => Cell::DateTime(datetime),
+            None => Cell::Float(dt.as_f64()),
+        },
+        Data::DateTimeIso(s) | Data::DurationIso(s) => Cell::Text(s.clone()),
+    }
+}
+
+/// Returns the start of Excel's (1900 based) date system, from which date
+/// cells hold the number of days as their serial number.
+fn get_excel_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd(1899, 12, 30).and_hms(0, 0, 0)
+}
+
+/// Returns the Excel serial number of a date cell
+fn serial(cell: &Cell) -> f64 {
+    cell.as_datetime().map_or(0.0, |dt| {
+        (dt - get_excel_epoch()).num_milliseconds() as f64 / MILLISECONDS_PER_DAY
+    })
+}
+
+/// Writes the sheet as a workbook with a single worksheet.
+pub fn write_sheet(sheet: &Sheet, dest: &mut dyn Write) -> Result<()> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    if !sheet.name.is_empty() {
+        worksheet.set_name(&sheet.name)?;
+    }
+
+    let header_format = Format::new().set_bold();
+    let date_format = Format::new().set_num_format("yyyy-mm-dd");
+    let datetime_format = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    for (col, column) in sheet.columns.iter().enumerate() {
+        worksheet.write_string_with_format(0, col as u16, column, &header_format)?;
+    }
+    for (row, values) in sheet.rows.iter().enumerate() {
+        let row = row as u32 + 1;
+        for (col, value) in values.iter().enumerate() {
+            let col = col as u16;
+            match value {
+                Cell::Empty => continue,
+                Cell::Bool(b) => worksheet.write_boolean(row, col, *b)?,
+                Cell::Int(i) => worksheet.write_number(row, col, *i as f64)?,
+                Cell::Float(f) => worksheet.write_number(row, col, *f)?,
+                Cell::Text(s) => worksheet.write_string(row, col, s)?,
+                Cell::Date(_) => {
+                    worksheet.write_number_with_format(row, col, serial(value), &date_format)?
+                }
+                Cell::DateTime(_) => {
+                    worksheet.write_number_with_format(row, col, serial(value), &datetime_format)?
+                }
+            };
+        }
+    }
+    worksheet.autofit();
+
+    dest.write_all(&workbook.save_to_buffer()?)?;
+    Ok(())
+}